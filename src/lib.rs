@@ -1,21 +1,29 @@
-use basebits::{hamming_dist_none, BaseBits};
+use basebits::{hamming_dist_none, BaseBits, ENCODING_LENGTH, MAX_BASES};
+use clap::ArgMatches;
 //use rayon::iter::ParBridge;
 use rayon::prelude::*;
 use rust_htslib::bam::errors::Error;
-use rust_htslib::bam::record::{Aux, Cigar, CigarString};
+use rust_htslib::bam::record::{Aux, Cigar, CigarString, CigarStringView};
 use rust_htslib::bam::{self, Read};
-use std::cmp::Ordering;
+use serde::Serialize;
+use std::any::Any;
+use std::borrow::Cow;
+use std::cmp::{Ordering, Reverse};
 use std::collections::hash_map::{Entry::Occupied, Entry::Vacant};
-use std::collections::{BTreeMap, HashMap, HashSet, VecDeque};
-use std::fmt::Display;
-use std::process;
-use std::sync::mpsc::channel;
+use std::collections::{BTreeMap, BTreeSet, BinaryHeap, HashMap, HashSet, VecDeque};
+use std::error;
+use std::fmt::{self, Display};
+use std::fs::File;
+use std::io::{self, BufWriter, Write};
+use std::str::FromStr;
+use std::sync::mpsc::sync_channel;
 use std::sync::{Arc, Mutex};
+use std::thread;
 
 #[cfg(test)]
 mod test;
 
-#[derive(Debug)]
+#[derive(Debug, Clone, Serialize)]
 pub struct Config {
     pub allowed_read_dist: u32,
     pub allowed_count_factor: u32,
@@ -23,10 +31,716 @@ pub struct Config {
     pub umi_tag: String,
     pub input_bam: String,
     pub output_bam: String,
+    pub discarded: Option<String>,
     pub umi_in_read_id: bool,
     pub ignore_splice_pos: bool,
+    pub per_cell: bool,
+    pub cell_tag: String,
+    /// Only set by the builder, which reads in the whitelist from it, so the two can't disagree
+    cell_whitelist: Option<String>,
+    pub raw_cell_tag: String,
+    pub cell_qual_tag: String,
+    pub per_gene: bool,
+    pub gene_tag: String,
     pub group_only: bool,
+    pub mark_duplicates: bool,
     pub is_paired: bool,
+    pub pair_key: PairKey,
+    pub keep_chimeric: bool,
+    pub secondary: AlignmentPolicy,
+    pub supplementary: AlignmentPolicy,
+    pub method: GroupingMethod,
+    pub index_threshold: usize,
+    pub bundle_window: i32,
+    pub unordered: bool,
+    pub missing_umi: UmiPolicy,
+    pub invalid_umi: UmiPolicy,
+    pub stats: Option<String>,
+    pub stats_format: StatsFormat,
+    pub output_stats: Option<String>,
+    pub count_output: String,
+    pub count_format: CountFormat,
+    pub family_size_tag: Option<String>,
+    pub umi_count_tag: Option<String>,
+    pub consensus: bool,
+    pub duplex: bool,
+    pub group_tag: String,
+    pub corrected_umi_tag: String,
+    pub original_umi_tag: String,
+    pub discard_tag: String,
+    /// The barcodes read from cell_whitelist
+    #[serde(skip)]
+    whitelist: Option<Arc<CellWhitelist>>,
+}
+
+impl Config {
+    /// A builder for a Config, starting from the same defaults as the rumi binary.
+    pub fn builder() -> ConfigBuilder {
+        ConfigBuilder {
+            config: Config {
+                allowed_read_dist: 1,
+                allowed_count_factor: 2,
+                allowed_network_depth: 2,
+                umi_tag: String::from("RX"),
+                input_bam: String::from("-"),
+                output_bam: String::from("-"),
+                discarded: None,
+                umi_in_read_id: false,
+                ignore_splice_pos: false,
+                per_cell: false,
+                cell_tag: String::from("CB"),
+                cell_whitelist: None,
+                raw_cell_tag: String::from("CR"),
+                cell_qual_tag: String::from("CY"),
+                per_gene: false,
+                gene_tag: String::from("XT"),
+                group_only: false,
+                mark_duplicates: false,
+                is_paired: false,
+                pair_key: PairKey::Tlen,
+                keep_chimeric: false,
+                secondary: AlignmentPolicy::Drop,
+                supplementary: AlignmentPolicy::Drop,
+                method: GroupingMethod::Directional,
+                index_threshold: 100,
+                bundle_window: 1000,
+                unordered: false,
+                missing_umi: UmiPolicy::Error,
+                invalid_umi: UmiPolicy::Group,
+                stats: None,
+                stats_format: StatsFormat::Json,
+                output_stats: None,
+                count_output: String::from("-"),
+                count_format: CountFormat::Tsv,
+                family_size_tag: None,
+                umi_count_tag: None,
+                consensus: false,
+                duplex: false,
+                group_tag: String::from("MI"),
+                corrected_umi_tag: String::from("RX"),
+                original_umi_tag: String::from("OX"),
+                discard_tag: String::from("XD"),
+                whitelist: None,
+            },
+        }
+    }
+
+    /// Create a Config from the arguments parsed by the rumi binary. Any argument that wasn't
+    /// given keeps its default.
+    pub fn from_args(matches: &ArgMatches) -> Result<Config, RumiError> {
+        fn parse<T: FromStr>(matches: &ArgMatches, name: &str) -> Result<Option<T>, RumiError> {
+            match matches.value_of(name) {
+                Some(value) => value.parse().map(Some).map_err(|_| {
+                    RumiError::InvalidConfig(format!("Invalid value for {}: {}", name, value))
+                }),
+                None => Ok(None),
+            }
+        }
+
+        let mut builder = Config::builder()
+            .umi_in_read_id(matches.is_present("umi_in_read_id"))
+            .ignore_splice_pos(matches.is_present("ignore_splice_pos"))
+            .per_cell(matches.is_present("per_cell"))
+            .per_gene(matches.is_present("per_gene"))
+            .group_only(matches.is_present("group_only"))
+            .mark_duplicates(matches.is_present("mark_duplicates"))
+            .is_paired(matches.is_present("is_paired"))
+            .keep_chimeric(matches.is_present("keep_chimeric"))
+            .unordered(matches.is_present("unordered"))
+            .consensus(matches.is_present("consensus"))
+            .duplex(matches.is_present("duplex"));
+        if let Some(input_bam) = matches.value_of("INBAM") {
+            builder = builder.input_bam(input_bam);
+        }
+        if let Some(output_bam) = matches.value_of("OUTBAM") {
+            builder = builder.output_bam(output_bam);
+        }
+        if let Some(path) = matches.value_of("discarded") {
+            builder = builder.discarded(path);
+        }
+        if let Some(umi_tag) = matches.value_of("umi_tag") {
+            builder = builder.umi_tag(umi_tag);
+        }
+        if let Some(tag) = matches.value_of("cell_tag") {
+            builder = builder.cell_tag(tag);
+        }
+        if let Some(path) = matches.value_of("cell_whitelist") {
+            builder = builder.cell_whitelist(path);
+        }
+        if let Some(tag) = matches.value_of("raw_cell_tag") {
+            builder = builder.raw_cell_tag(tag);
+        }
+        if let Some(tag) = matches.value_of("cell_qual_tag") {
+            builder = builder.cell_qual_tag(tag);
+        }
+        if let Some(tag) = matches.value_of("gene_tag") {
+            builder = builder.gene_tag(tag);
+        }
+        if let Some(dist) = parse(matches, "allowed_read_dist")? {
+            builder = builder.allowed_read_dist(dist);
+        }
+        if let Some(factor) = parse(matches, "allowed_count_factor")? {
+            builder = builder.allowed_count_factor(factor);
+        }
+        if let Some(depth) = parse(matches, "allowed_network_depth")? {
+            builder = builder.allowed_network_depth(depth);
+        }
+        if let Some(method) = parse(matches, "method")? {
+            builder = builder.method(method);
+        }
+        if let Some(threshold) = parse(matches, "index_threshold")? {
+            builder = builder.index_threshold(threshold);
+        }
+        if let Some(window) = parse(matches, "bundle_window")? {
+            builder = builder.bundle_window(window);
+        }
+        if let Some(key) = parse(matches, "pair_key")? {
+            builder = builder.pair_key(key);
+        }
+        if let Some(policy) = parse(matches, "secondary")? {
+            builder = builder.secondary(policy);
+        }
+        if let Some(policy) = parse(matches, "supplementary")? {
+            builder = builder.supplementary(policy);
+        }
+        if let Some(policy) = parse(matches, "missing_umi")? {
+            builder = builder.missing_umi(policy);
+        }
+        if let Some(policy) = parse(matches, "invalid_umi")? {
+            builder = builder.invalid_umi(policy);
+        }
+        if let Some(stats) = matches.value_of("stats") {
+            builder = builder.stats(stats);
+        }
+        if let Some(format) = parse(matches, "stats_format")? {
+            builder = builder.stats_format(format);
+        }
+        if let Some(prefix) = matches.value_of("output_stats") {
+            builder = builder.output_stats(prefix);
+        }
+        if let Some(output) = matches.value_of("OUTPUT") {
+            builder = builder.count_output(output);
+        }
+        if let Some(format) = parse(matches, "count_format")? {
+            builder = builder.count_format(format);
+        }
+        if let Some(tag) = matches.value_of("family_size_tag") {
+            builder = builder.family_size_tag(tag);
+        }
+        if let Some(tag) = matches.value_of("umi_count_tag") {
+            builder = builder.umi_count_tag(tag);
+        }
+        if let Some(tag) = matches.value_of("group_tag") {
+            builder = builder.group_tag(tag);
+        }
+        if let Some(tag) = matches.value_of("corrected_umi_tag") {
+            builder = builder.corrected_umi_tag(tag);
+        }
+        if let Some(tag) = matches.value_of("original_umi_tag") {
+            builder = builder.original_umi_tag(tag);
+        }
+        if let Some(tag) = matches.value_of("discard_tag") {
+            builder = builder.discard_tag(tag);
+        }
+        builder.build()
+    }
+}
+
+/// Builds a Config, checking that the settings make sense together. See the rumi binary's help
+/// for what each setting does.
+#[derive(Debug, Clone)]
+pub struct ConfigBuilder {
+    config: Config,
+}
+
+impl ConfigBuilder {
+    pub fn allowed_read_dist(mut self, dist: u32) -> Self {
+        self.config.allowed_read_dist = dist;
+        self
+    }
+
+    pub fn allowed_count_factor(mut self, factor: u32) -> Self {
+        self.config.allowed_count_factor = factor;
+        self
+    }
+
+    pub fn allowed_network_depth(mut self, depth: usize) -> Self {
+        self.config.allowed_network_depth = depth;
+        self
+    }
+
+    pub fn umi_tag(mut self, tag: &str) -> Self {
+        self.config.umi_tag = String::from(tag);
+        self
+    }
+
+    pub fn input_bam(mut self, path: &str) -> Self {
+        self.config.input_bam = String::from(path);
+        self
+    }
+
+    pub fn output_bam(mut self, path: &str) -> Self {
+        self.config.output_bam = String::from(path);
+        self
+    }
+
+    pub fn discarded(mut self, path: &str) -> Self {
+        self.config.discarded = Some(String::from(path));
+        self
+    }
+
+    pub fn umi_in_read_id(mut self, umi_in_read_id: bool) -> Self {
+        self.config.umi_in_read_id = umi_in_read_id;
+        self
+    }
+
+    pub fn ignore_splice_pos(mut self, ignore_splice_pos: bool) -> Self {
+        self.config.ignore_splice_pos = ignore_splice_pos;
+        self
+    }
+
+    pub fn per_cell(mut self, per_cell: bool) -> Self {
+        self.config.per_cell = per_cell;
+        self
+    }
+
+    pub fn cell_tag(mut self, tag: &str) -> Self {
+        self.config.cell_tag = String::from(tag);
+        self
+    }
+
+    pub fn cell_whitelist(mut self, path: &str) -> Self {
+        self.config.cell_whitelist = Some(String::from(path));
+        self
+    }
+
+    pub fn raw_cell_tag(mut self, tag: &str) -> Self {
+        self.config.raw_cell_tag = String::from(tag);
+        self
+    }
+
+    pub fn cell_qual_tag(mut self, tag: &str) -> Self {
+        self.config.cell_qual_tag = String::from(tag);
+        self
+    }
+
+    pub fn per_gene(mut self, per_gene: bool) -> Self {
+        self.config.per_gene = per_gene;
+        self
+    }
+
+    pub fn gene_tag(mut self, tag: &str) -> Self {
+        self.config.gene_tag = String::from(tag);
+        self
+    }
+
+    pub fn group_only(mut self, group_only: bool) -> Self {
+        self.config.group_only = group_only;
+        self
+    }
+
+    pub fn mark_duplicates(mut self, mark_duplicates: bool) -> Self {
+        self.config.mark_duplicates = mark_duplicates;
+        self
+    }
+
+    pub fn is_paired(mut self, is_paired: bool) -> Self {
+        self.config.is_paired = is_paired;
+        self
+    }
+
+    pub fn method(mut self, method: GroupingMethod) -> Self {
+        self.config.method = method;
+        self
+    }
+
+    pub fn index_threshold(mut self, threshold: usize) -> Self {
+        self.config.index_threshold = threshold;
+        self
+    }
+
+    pub fn bundle_window(mut self, window: i32) -> Self {
+        self.config.bundle_window = window;
+        self
+    }
+
+    pub fn unordered(mut self, unordered: bool) -> Self {
+        self.config.unordered = unordered;
+        self
+    }
+
+    pub fn missing_umi(mut self, policy: UmiPolicy) -> Self {
+        self.config.missing_umi = policy;
+        self
+    }
+
+    pub fn invalid_umi(mut self, policy: UmiPolicy) -> Self {
+        self.config.invalid_umi = policy;
+        self
+    }
+
+    pub fn pair_key(mut self, key: PairKey) -> Self {
+        self.config.pair_key = key;
+        self
+    }
+
+    pub fn keep_chimeric(mut self, keep_chimeric: bool) -> Self {
+        self.config.keep_chimeric = keep_chimeric;
+        self
+    }
+
+    pub fn secondary(mut self, policy: AlignmentPolicy) -> Self {
+        self.config.secondary = policy;
+        self
+    }
+
+    pub fn supplementary(mut self, policy: AlignmentPolicy) -> Self {
+        self.config.supplementary = policy;
+        self
+    }
+
+    pub fn stats(mut self, path: &str) -> Self {
+        self.config.stats = Some(String::from(path));
+        self
+    }
+
+    pub fn stats_format(mut self, format: StatsFormat) -> Self {
+        self.config.stats_format = format;
+        self
+    }
+
+    pub fn output_stats(mut self, prefix: &str) -> Self {
+        self.config.output_stats = Some(String::from(prefix));
+        self
+    }
+
+    pub fn count_output(mut self, output: &str) -> Self {
+        self.config.count_output = String::from(output);
+        self
+    }
+
+    pub fn count_format(mut self, format: CountFormat) -> Self {
+        self.config.count_format = format;
+        self
+    }
+
+    pub fn family_size_tag(mut self, tag: &str) -> Self {
+        self.config.family_size_tag = Some(String::from(tag));
+        self
+    }
+
+    pub fn umi_count_tag(mut self, tag: &str) -> Self {
+        self.config.umi_count_tag = Some(String::from(tag));
+        self
+    }
+
+    pub fn consensus(mut self, consensus: bool) -> Self {
+        self.config.consensus = consensus;
+        self
+    }
+
+    pub fn duplex(mut self, duplex: bool) -> Self {
+        self.config.duplex = duplex;
+        self
+    }
+
+    pub fn group_tag(mut self, tag: &str) -> Self {
+        self.config.group_tag = String::from(tag);
+        self
+    }
+
+    pub fn corrected_umi_tag(mut self, tag: &str) -> Self {
+        self.config.corrected_umi_tag = String::from(tag);
+        self
+    }
+
+    pub fn original_umi_tag(mut self, tag: &str) -> Self {
+        self.config.original_umi_tag = String::from(tag);
+        self
+    }
+
+    pub fn discard_tag(mut self, tag: &str) -> Self {
+        self.config.discard_tag = String::from(tag);
+        self
+    }
+
+    /// Check the settings and create the Config, reading in the cell whitelist if there is one.
+    pub fn build(self) -> Result<Config, RumiError> {
+        let mut config = self.config;
+        if config.allowed_network_depth < 1 {
+            return Err(RumiError::InvalidConfig(String::from(
+                "allowed_network_depth must be at least 1",
+            )));
+        }
+        if config.allowed_count_factor < 1 {
+            return Err(RumiError::InvalidConfig(String::from(
+                "allowed_count_factor must be at least 1",
+            )));
+        }
+        if config.bundle_window < 0 {
+            return Err(RumiError::InvalidConfig(String::from(
+                "bundle_window can't be negative",
+            )));
+        }
+        if !config.umi_in_read_id && config.umi_tag.len() != 2 {
+            return Err(RumiError::InvalidConfig(format!(
+                "umi_tag must be two characters: {}",
+                config.umi_tag
+            )));
+        }
+        if config.consensus && config.group_only {
+            return Err(RumiError::InvalidConfig(String::from(
+                "consensus can't be used with group_only",
+            )));
+        }
+        if config.duplex && config.is_paired {
+            return Err(RumiError::InvalidConfig(String::from(
+                "duplex can't be used with is_paired, since the strands only start at the same \
+                 position when mates are grouped on their own",
+            )));
+        }
+        if config.missing_umi == UmiPolicy::Group {
+            return Err(RumiError::InvalidConfig(String::from(
+                "reads without a umi can't be grouped as they are",
+            )));
+        }
+        if config.mark_duplicates && (config.group_only || config.consensus) {
+            return Err(RumiError::InvalidConfig(String::from(
+                "mark_duplicates can't be used with group_only or consensus",
+            )));
+        }
+        if config.mark_duplicates && config.discarded.is_some() {
+            return Err(RumiError::InvalidConfig(String::from(
+                "mark_duplicates writes every read, so there is nothing to write to discarded",
+            )));
+        }
+        if config.count_format == CountFormat::Mtx && !config.per_cell {
+            return Err(RumiError::InvalidConfig(String::from(
+                "count_format mtx needs per_cell",
+            )));
+        }
+        let tags = [
+            config.family_size_tag.as_ref(),
+            config.umi_count_tag.as_ref(),
+            Some(&config.group_tag),
+            Some(&config.corrected_umi_tag),
+            Some(&config.original_umi_tag),
+            Some(&config.discard_tag),
+            Some(&config.cell_tag),
+            Some(&config.raw_cell_tag),
+            Some(&config.cell_qual_tag),
+            Some(&config.gene_tag),
+        ];
+        if let Some(tag) = tags.iter().filter_map(|&t| t).find(|t| t.len() != 2) {
+            return Err(RumiError::InvalidConfig(format!(
+                "tags must be two characters: {}",
+                tag
+            )));
+        }
+        if config.group_tag == config.corrected_umi_tag
+            || config.group_tag == config.original_umi_tag
+            || config.corrected_umi_tag == config.original_umi_tag
+        {
+            return Err(RumiError::InvalidConfig(String::from(
+                "group_tag, corrected_umi_tag and original_umi_tag must be different",
+            )));
+        }
+        if config.discard_tag == config.group_tag {
+            return Err(RumiError::InvalidConfig(String::from(
+                "discard_tag and group_tag must be different",
+            )));
+        }
+        if config.input_bam.is_empty() || config.output_bam.is_empty() {
+            return Err(RumiError::InvalidConfig(String::from(
+                "input and output bams must be given",
+            )));
+        }
+        if let Some(path) = &config.cell_whitelist {
+            if !config.per_cell {
+                return Err(RumiError::InvalidConfig(String::from(
+                    "cell_whitelist needs per_cell",
+                )));
+            }
+            config.whitelist = Some(Arc::new(CellWhitelist::from_path(path)?));
+        }
+        Ok(config)
+    }
+}
+
+/// The method used to group umis at a position. These mirror the methods offered by umi_tools.
+#[derive(Debug, Clone, Copy, PartialEq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum GroupingMethod {
+    /// Every distinct umi is its own group.
+    Unique,
+    /// Every distinct umi is its own group, but umis with counts at or below 1% of the median
+    /// count at the position are dropped.
+    Percentile,
+    /// Umis within allowed_read_dist of each other are connected, and each connected component
+    /// forms a group.
+    Cluster,
+    /// Connected components are split into groups around the fewest most abundant umis that
+    /// account for every umi in the component.
+    Adjacency,
+    /// Directional adjacency, where edges only form from a umi to a much less abundant umi.
+    Directional,
+}
+
+impl Display for GroupingMethod {
+    fn fmt(&self, fmt: &mut fmt::Formatter) -> fmt::Result {
+        let name = match self {
+            GroupingMethod::Unique => "unique",
+            GroupingMethod::Percentile => "percentile",
+            GroupingMethod::Cluster => "cluster",
+            GroupingMethod::Adjacency => "adjacency",
+            GroupingMethod::Directional => "directional",
+        };
+        write!(fmt, "{}", name)
+    }
+}
+
+impl FromStr for GroupingMethod {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "unique" => Ok(GroupingMethod::Unique),
+            "percentile" => Ok(GroupingMethod::Percentile),
+            "cluster" => Ok(GroupingMethod::Cluster),
+            "adjacency" => Ok(GroupingMethod::Adjacency),
+            "directional" => Ok(GroupingMethod::Directional),
+            _ => Err(format!("Unknown grouping method: {}", s)),
+        }
+    }
+}
+
+/// What to do with a read that has no umi, or whose umi has bases other than ACGT or is too long
+/// to encode.
+#[derive(Debug, Clone, Copy, PartialEq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum UmiPolicy {
+    /// Stop with an error.
+    Error,
+    /// Drop the read.
+    Skip,
+    /// Write the read out untouched, without grouping it.
+    Passthrough,
+    /// Group the read with the reads at its position that have the same umi, but never with
+    /// reads that have other umis. Reads without a umi, or with a umi too long to encode, share
+    /// one group at each position.
+    OwnGroup,
+    /// Group the read like any other, with bases other than ACGT read as N's, which never match.
+    /// Umis too long to encode are an error. Only for invalid umis.
+    Group,
+}
+
+impl FromStr for UmiPolicy {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "error" => Ok(UmiPolicy::Error),
+            "skip" => Ok(UmiPolicy::Skip),
+            "passthrough" => Ok(UmiPolicy::Passthrough),
+            "own_group" => Ok(UmiPolicy::OwnGroup),
+            "group" => Ok(UmiPolicy::Group),
+            _ => Err(format!("Unknown umi policy: {}", s)),
+        }
+    }
+}
+
+/// How the position of a read pair is keyed when paired.
+#[derive(Debug, Clone, Copy, PartialEq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum PairKey {
+    /// The 5' position and strand of the first read, and the insert size.
+    Tlen,
+    /// The unclipped 5' positions and strands of both reads, like Picard and fgbio. The mate's
+    /// is worked out from its cigar in the MC tag.
+    Template,
+}
+
+impl FromStr for PairKey {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "tlen" => Ok(PairKey::Tlen),
+            "template" => Ok(PairKey::Template),
+            _ => Err(format!("Unknown pair key: {}", s)),
+        }
+    }
+}
+
+/// What to do with secondary or supplementary alignments, which are never grouped so that they
+/// can't be kept in place of their primary alignment.
+#[derive(Debug, Clone, Copy, PartialEq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum AlignmentPolicy {
+    /// Drop the alignment.
+    Drop,
+    /// Write the alignment out untouched.
+    Passthrough,
+    /// Write the alignment out only if its primary alignment is written, with the primary's
+    /// group tags. Paired alignments follow the first read of their pair.
+    Follow,
+}
+
+impl FromStr for AlignmentPolicy {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "drop" => Ok(AlignmentPolicy::Drop),
+            "passthrough" => Ok(AlignmentPolicy::Passthrough),
+            "follow" => Ok(AlignmentPolicy::Follow),
+            _ => Err(format!("Unknown alignment policy: {}", s)),
+        }
+    }
+}
+
+/// The format to write run stats in.
+#[derive(Debug, Clone, Copy, PartialEq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum StatsFormat {
+    /// A single json object.
+    Json,
+    /// A name and value per line, with nested names joined by dots.
+    Tsv,
+}
+
+impl FromStr for StatsFormat {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "json" => Ok(StatsFormat::Json),
+            "tsv" => Ok(StatsFormat::Tsv),
+            _ => Err(format!("Unknown stats format: {}", s)),
+        }
+    }
+}
+
+/// The format to write the counts of rumi count in.
+#[derive(Debug, Clone, Copy, PartialEq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum CountFormat {
+    /// A gene, cell and count per line, like umi_tools count.
+    Tsv,
+    /// A Matrix Market sparse matrix of genes by cells, with barcodes and features files.
+    Mtx,
+}
+
+impl FromStr for CountFormat {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "tsv" => Ok(CountFormat::Tsv),
+            "mtx" => Ok(CountFormat::Mtx),
+            _ => Err(format!("Unknown count format: {}", s)),
+        }
+    }
 }
 
 #[derive(Debug, Clone, PartialEq)]
@@ -43,6 +757,13 @@ pub struct Position {
     is_rev: bool,
     target: i32,
     tlen: Option<i32>,
+    /// The target, 5' position and strand of the mate, for PairKey::Template and chimeric pairs
+    mate: Option<(i32, i32, bool)>,
+    /// The cell barcode, with --per_cell
+    cell: Option<Vec<u8>>,
+    /// The gene id, with --per_gene. Reads of a gene are grouped together wherever they start,
+    /// so the rest of the position is left out.
+    gene: Option<Vec<u8>>,
 }
 
 impl PartialOrd for Position {
@@ -58,6 +779,11 @@ impl Ord for Position {
             return comp;
         }
 
+        let comp = self.gene.cmp(&other.gene);
+        if comp != Ordering::Equal {
+            return comp;
+        }
+
         let comp = self.pos.cmp(&other.pos);
         if comp != Ordering::Equal {
             return comp;
@@ -68,46 +794,73 @@ impl Ord for Position {
             return comp;
         }
 
+        let comp = self.mate.cmp(&other.mate);
+        if comp != Ordering::Equal {
+            return comp;
+        }
+
         let comp = self.is_spliced.cmp(&other.is_spliced);
         if comp != Ordering::Equal {
             return comp;
         }
 
-        self.is_rev.cmp(&other.is_rev)
+        let comp = self.is_rev.cmp(&other.is_rev);
+        if comp != Ordering::Equal {
+            return comp;
+        }
+
+        self.cell.cmp(&other.cell)
     }
 }
 
 impl Position {
     /// Takes a read and determins the position to use as a key in the returned group.
-    pub fn new(record: &bam::record::Record, ignore_splice_pos: bool, use_tlen: bool) -> Self {
-        let mut pos = record.pos();
+    pub fn new(record: &bam::record::Record, config: &Config) -> Self {
+        let cell = if config.per_cell {
+            cell_barcode(record, config).map(|cell| cell.to_vec())
+        } else {
+            None
+        };
+        if config.per_gene {
+            return Self {
+                pos: 0,
+                is_rev: false,
+                target: record.tid(),
+                is_spliced: None,
+                tlen: None,
+                mate: None,
+                cell,
+                gene: gene_id(record, config).map(|gene| gene.to_vec()),
+            };
+        }
+
         let mut is_spliced: Option<u32>;
-        let tlen: Option<i32>;
+        let mut tlen: Option<i32> = None;
+        let mut mate: Option<(i32, i32, bool)> = None;
         let cigarview = record.cigar();
         let cigar = &cigarview;
 
+        let pos = Position::five_prime_pos(record, &cigarview);
         if record.is_reverse() {
-            pos = cigarview.end_pos();
-            // if the end of the read was soft clipped, add that amount back to its pos
-            if let Cigar::SoftClip(num) = cigar[cigar.len() - 1] {
-                pos = pos + num as i32;
-            }
-
             is_spliced = Position::find_splice(&cigar, true);
         } else {
-            if let Cigar::SoftClip(num) = cigar[0] {
-                pos = pos - num as i32;
-            }
             is_spliced = Position::find_splice(&cigar, false);
         }
-        if ignore_splice_pos && is_spliced.is_some() {
+        if config.ignore_splice_pos && is_spliced.is_some() {
             is_spliced = Some(0);
         }
 
-        if use_tlen {
-            tlen = Some(record.insert_size());
-        } else {
-            tlen = None;
+        // The insert size of a chimeric pair is meaningless, so they always use the mate
+        if config.is_paired {
+            if config.pair_key == PairKey::Template || record.tid() != record.mtid() {
+                mate = Some((
+                    record.mtid(),
+                    Position::mate_five_prime_pos(record),
+                    record.is_mate_reverse(),
+                ));
+            } else {
+                tlen = Some(record.insert_size());
+            }
         }
         Self {
             pos: pos,
@@ -115,7 +868,109 @@ impl Position {
             target: record.tid(),
             is_spliced: is_spliced,
             tlen: tlen,
+            mate,
+            cell,
+            gene: None,
+        }
+    }
+
+    /// The 5' position of a read's mate including any soft clipping, from the cigar in its MC
+    /// tag. Without an MC tag the mate's soft clipping and length are unknown, so this is just
+    /// its pos.
+    fn mate_five_prime_pos(record: &bam::record::Record) -> i32 {
+        let mut pos = record.mpos();
+        let cigar = match record.aux(b"MC") {
+            Some(Aux::String(cigar)) => cigar,
+            _ => return pos,
+        };
+        // Split the cigar into its (length, op) pairs
+        let mut ops = vec![];
+        let mut len = 0;
+        for &c in cigar.iter() {
+            if c.is_ascii_digit() {
+                len = len * 10 + i32::from(c - b'0');
+            } else {
+                ops.push((len, c));
+                len = 0;
+            }
+        }
+        if record.is_mate_reverse() {
+            pos += ops
+                .iter()
+                .filter(|(_, op)| b"MDN=X".contains(op))
+                .map(|(len, _)| len)
+                .sum::<i32>();
+            if let Some((num, b'S')) = ops.last() {
+                pos += num;
+            }
+        } else if let Some((num, b'S')) = ops.first() {
+            pos -= num;
+        }
+        pos
+    }
+
+    /// Whether this is the position of a read pair whose reads are on different references.
+    pub fn is_chimeric(&self) -> bool {
+        match self.mate {
+            Some(mate) => mate.0 != self.target,
+            None => false,
+        }
+    }
+
+    /// An id for the group at this position with the given umi. Only one group at a position can
+    /// have a umi, so the id is unique across the file and the same from run to run. It's made up
+    /// of the target id, the position and strand, the splice, tlen and mate if used, the cell
+    /// barcode if used, and the umi. Chimeric pairs are grouped at both ends, so their two ends
+    /// are put in order, which makes the id the same at each end unless they are spliced. With
+    /// --per_gene the gene id takes the place of the position.
+    pub fn group_id(&self, umi: &[u8]) -> String {
+        let strand = |is_rev| if is_rev { '-' } else { '+' };
+        let end = (self.target, self.pos, self.is_rev);
+        let (end, mate) = match self.mate {
+            Some(mate) if self.is_chimeric() && mate < end => (mate, Some(end)),
+            mate => (end, mate),
+        };
+        let mut id = match &self.gene {
+            Some(gene) => format!("{}:g{}", self.target, String::from_utf8_lossy(gene)),
+            None => format!("{}:{}:{}", end.0, end.1, strand(end.2)),
+        };
+        if let Some(splice) = self.is_spliced {
+            id.push_str(&format!(":s{}", splice));
+        }
+        if let Some(tlen) = self.tlen {
+            id.push_str(&format!(":t{}", tlen));
+        }
+        if let Some((mate_target, mate_pos, mate_rev)) = mate {
+            id.push_str(&format!(
+                ":m{}:{}{}",
+                mate_target,
+                mate_pos,
+                strand(mate_rev)
+            ));
         }
+        if let Some(cell) = &self.cell {
+            id.push_str(":c");
+            id.push_str(&String::from_utf8_lossy(cell));
+        }
+        id.push(':');
+        id.push_str(&String::from_utf8_lossy(umi));
+        id
+    }
+
+    /// The 5' position of a read including any soft clipping. This is the start of forward reads
+    /// and the end of reverse reads.
+    fn five_prime_pos(record: &bam::record::Record, cigar: &CigarStringView) -> i32 {
+        let mut pos = record.pos();
+        if record.is_reverse() {
+            pos = cigar.end_pos();
+            // if the end of the read was soft clipped, add that amount back to its pos
+            if let Cigar::SoftClip(num) = cigar[cigar.len() - 1] {
+                pos = pos + num as i32;
+            }
+        } else if let Cigar::SoftClip(num) = cigar[0] {
+            pos = pos - num as i32;
+        }
+        pos
     }
 
     /// Takes a cigar string and finds the first splice postion as an offset from the start.
@@ -155,6 +1010,16 @@ pub enum ReadCollection {
     ManyReads(Vec<bam::record::Record>),
 }
 
+impl ReadCollection {
+    /// The reads in the collection.
+    fn reads(&self) -> &[bam::record::Record] {
+        match self {
+            ReadCollection::SingleRead(read) => std::slice::from_ref(read),
+            ReadCollection::ManyReads(reads) => reads,
+        }
+    }
+}
+
 /// A Read or Reads and the number of times that read signature has been seen
 /// Read signature meaning Position + UMI
 #[derive(Debug, Clone, PartialEq)]
@@ -171,8 +1036,15 @@ pub struct Group<'a> {
     master_node: usize,
 }
 
-#[derive(Debug)]
-pub enum RecordEvent {
+impl<'a> Group<'a> {
+    /// The reads in the group, by node.
+    fn reads(&self) -> impl Iterator<Item = &'a bam::record::Record> + '_ {
+        self.nodes.iter().flat_map(|node| node.freq.read.reads())
+    }
+}
+
+#[derive(Debug)]
+pub enum RecordEvent {
     RecordMapped,
     RecordUnmapped,
     RecordUnpaired,
@@ -180,7 +1052,87 @@ pub enum RecordEvent {
     RecordChimeric,
 }
 
-#[derive(Debug)]
+/// Why a read was written to the discarded bam instead of the output.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Discard {
+    /// Another read was kept for its group.
+    Duplicate,
+    /// Its group was collapsed into a consensus read.
+    Consensus,
+    /// Its umi was too rare at its position to be grouped by the percentile method.
+    RareUmi,
+    Unmapped,
+    Unpaired,
+    MateUnmapped,
+    Chimeric,
+    Secondary,
+    Supplementary,
+    MissingUmi,
+    InvalidUmi,
+    MissingCell,
+    UncorrectableCell,
+    MissingGene,
+}
+
+impl Display for Discard {
+    fn fmt(&self, fmt: &mut fmt::Formatter) -> fmt::Result {
+        let reason = match self {
+            Discard::Duplicate => "duplicate",
+            Discard::Consensus => "consensus",
+            Discard::RareUmi => "rare_umi",
+            Discard::Unmapped => "unmapped",
+            Discard::Unpaired => "unpaired",
+            Discard::MateUnmapped => "mate_unmapped",
+            Discard::Chimeric => "chimeric",
+            Discard::Secondary => "secondary",
+            Discard::Supplementary => "supplementary",
+            Discard::MissingUmi => "missing_umi",
+            Discard::InvalidUmi => "invalid_umi",
+            Discard::MissingCell => "missing_cell",
+            Discard::UncorrectableCell => "uncorrectable_cell",
+            Discard::MissingGene => "missing_gene",
+        };
+        write!(fmt, "{}", reason)
+    }
+}
+
+/// Drop a read that isn't grouped, or pass it through unmarked when marking duplicates, since
+/// then every read is written.
+fn drop_read(
+    mut read: bam::record::Record,
+    reason: Discard,
+    config: &Config,
+    passthrough: &mut Vec<bam::record::Record>,
+    discarded: &mut Vec<bam::record::Record>,
+) {
+    if config.mark_duplicates {
+        read.unset_duplicate();
+        passthrough.push(read);
+    } else {
+        discard(read, reason, config, discarded);
+    }
+}
+
+/// Tag a read with why it was discarded and add it to discarded, if a discarded bam is being
+/// written.
+fn discard(
+    mut read: bam::record::Record,
+    reason: Discard,
+    config: &Config,
+    discarded: &mut Vec<bam::record::Record>,
+) {
+    if config.discarded.is_some() {
+        let reason = reason.to_string();
+        set_aux(
+            &mut read,
+            config.discard_tag.as_bytes(),
+            &Aux::String(reason.as_bytes()),
+        );
+        discarded.push(read);
+    }
+}
+
+#[derive(Debug, Serialize)]
 pub struct Stats {
     reads_in: u32,
     reads_out: u32,
@@ -188,6 +1140,18 @@ pub struct Stats {
     reads_unpaired: u32,
     mate_unmapped: u32,
     chimeric: u32,
+    secondary: u32,
+    supplementary: u32,
+    missing_umi: u32,
+    invalid_umi: u32,
+    missing_cell: u32,
+    missing_gene: u32,
+    /// Reads whose cell barcode was one mismatch from a whitelisted barcode, and corrected to it
+    cell_corrected: u32,
+    /// Reads whose cell barcode wasn't whitelisted, and couldn't be corrected to one that is
+    cell_uncorrectable: u32,
+    /// For each number of reads, how many groups had that many reads.
+    family_sizes: BTreeMap<u32, u64>,
 }
 
 impl Stats {
@@ -199,6 +1163,15 @@ impl Stats {
             reads_unpaired: 0,
             mate_unmapped: 0,
             chimeric: 0,
+            secondary: 0,
+            supplementary: 0,
+            missing_umi: 0,
+            invalid_umi: 0,
+            missing_cell: 0,
+            missing_gene: 0,
+            cell_corrected: 0,
+            cell_uncorrectable: 0,
+            family_sizes: BTreeMap::new(),
         }
     }
     pub fn update(&mut self, other: &Self) {
@@ -208,200 +1181,1880 @@ impl Stats {
         self.reads_unpaired += other.reads_unpaired;
         self.mate_unmapped += other.mate_unmapped;
         self.chimeric += other.chimeric;
+        self.secondary += other.secondary;
+        self.supplementary += other.supplementary;
+        self.missing_umi += other.missing_umi;
+        self.invalid_umi += other.invalid_umi;
+        self.missing_cell += other.missing_cell;
+        self.missing_gene += other.missing_gene;
+        self.cell_corrected += other.cell_corrected;
+        self.cell_uncorrectable += other.cell_uncorrectable;
+        for (&size, &count) in other.family_sizes.iter() {
+            *self.family_sizes.entry(size).or_insert(0) += count;
+        }
     }
+
+    /// Count the reads in each of the groups found at a position.
+    fn add_groups(&mut self, groups: &[Group]) {
+        for group in groups.iter() {
+            let size = group.nodes.iter().map(|n| n.freq.freq).sum();
+            *self.family_sizes.entry(size).or_insert(0) += 1;
+        }
+    }
+
+    /// The number of groups, and the number of reads in them.
+    fn grouped(&self) -> (u64, u64) {
+        self.family_sizes
+            .iter()
+            .fold((0, 0), |(groups, reads), (&size, &count)| {
+                (groups + count, reads + u64::from(size) * count)
+            })
+    }
+
+    /// The fraction of grouped reads that are duplicates of another read in their group.
+    pub fn duplication_rate(&self) -> f64 {
+        let (groups, reads) = self.grouped();
+        if reads == 0 {
+            0.0
+        } else {
+            1.0 - groups as f64 / reads as f64
+        }
+    }
+
+    /// The number of unique molecules in the library, estimated from the number of reads and
+    /// groups the same way as Picard's EstimateLibraryComplexity. None if there are no duplicates.
+    pub fn estimated_library_size(&self) -> Option<u64> {
+        let (groups, reads) = self.grouped();
+        let (c, n) = (groups as f64, reads as f64);
+        // The expected number of unique molecules seen from n reads of a library of size x is
+        // x * (1 - e^(-n/x)). Solve for where that equals c.
+        let f = |x: f64| c / x - 1.0 + (-n / x).exp();
+        if groups == 0 || groups >= reads || f(c) < 0.0 {
+            return None;
+        }
+        let mut lower = 1.0;
+        let mut upper = 100.0;
+        while f(upper * c) > 0.0 {
+            upper *= 10.0;
+        }
+        for _ in 0..40 {
+            let mid = (lower + upper) / 2.0;
+            let u = f(mid * c);
+            if u == 0.0 {
+                break;
+            } else if u > 0.0 {
+                lower = mid;
+            } else {
+                upper = mid;
+            }
+        }
+        Some((c * (lower + upper) / 2.0) as u64)
+    }
+}
+
+/// The stats for a run along with the settings it was run with, as written to the stats file.
+#[derive(Serialize)]
+struct RunStats<'a> {
+    version: &'static str,
+    parameters: &'a Config,
+    stats: &'a Stats,
+    duplication_rate: f64,
+    estimated_library_size: Option<u64>,
+}
+
+/// Write the stats for a run to the stats file in the configured format, if there is one.
+pub fn write_stats(stats: &Stats, config: &Config) -> Result<(), RumiError> {
+    let path = match &config.stats {
+        Some(path) => path,
+        None => return Ok(()),
+    };
+    let run_stats = RunStats {
+        version: env!("CARGO_PKG_VERSION"),
+        parameters: config,
+        stats,
+        duplication_rate: stats.duplication_rate(),
+        estimated_library_size: stats.estimated_library_size(),
+    };
+    let mut writer = BufWriter::new(File::create(path)?);
+    match config.stats_format {
+        StatsFormat::Json => {
+            serde_json::to_writer_pretty(&mut writer, &run_stats).map_err(io::Error::from)?;
+            writeln!(writer)?;
+        }
+        StatsFormat::Tsv => {
+            let value = serde_json::to_value(&run_stats).map_err(io::Error::from)?;
+            writeln!(writer, "name\tvalue")?;
+            write_tsv_rows(&mut writer, "", &value)?;
+        }
+    }
+    writer.flush()?;
+    Ok(())
+}
+
+/// Write a row for each value in a json value, naming nested values by their path joined by dots.
+fn write_tsv_rows<W: Write>(
+    writer: &mut W,
+    name: &str,
+    value: &serde_json::Value,
+) -> Result<(), io::Error> {
+    let join = |key: &str| {
+        if name.is_empty() {
+            key.to_string()
+        } else {
+            format!("{}.{}", name, key)
+        }
+    };
+    match value {
+        serde_json::Value::Object(map) => {
+            for (key, value) in map.iter() {
+                write_tsv_rows(writer, &join(key), value)?;
+            }
+        }
+        serde_json::Value::Array(values) => {
+            for (i, value) in values.iter().enumerate() {
+                write_tsv_rows(writer, &join(&i.to_string()), value)?;
+            }
+        }
+        serde_json::Value::String(value) => writeln!(writer, "{}\t{}", name, value)?,
+        serde_json::Value::Null => writeln!(writer, "{}\t", name)?,
+        value => writeln!(writer, "{}\t{}", name, value)?,
+    }
+    Ok(())
 }
 
 impl Display for Stats {
-    fn fmt(&self, fmt: &mut std::fmt::Formatter) -> std::result::Result<(), std::fmt::Error> {
-        write!(fmt, "Reads In: {}\nReads Out: {}\nReads Unmapped: {}\nReads Unpaired: {}\nMates Unmapped: {}\nReads Chimeric: {}", self.reads_in, self.reads_out, self.reads_unmapped, self.reads_unpaired, self.mate_unmapped, self.chimeric)
+    fn fmt(&self, fmt: &mut fmt::Formatter) -> fmt::Result {
+        write!(fmt, "Reads In: {}\nReads Out: {}\nReads Unmapped: {}\nReads Unpaired: {}\nMates Unmapped: {}\nReads Chimeric: {}\nReads Secondary: {}\nReads Supplementary: {}\nReads Missing Umi: {}\nReads Invalid Umi: {}\nReads Missing Cell: {}\nReads Missing Gene: {}\nReads Cell Corrected: {}\nReads Cell Uncorrectable: {}", self.reads_in, self.reads_out, self.reads_unmapped, self.reads_unpaired, self.mate_unmapped, self.chimeric, self.secondary, self.supplementary, self.missing_umi, self.invalid_umi, self.missing_cell, self.missing_gene, self.cell_corrected, self.cell_uncorrectable)?;
+        let library_size = match self.estimated_library_size() {
+            Some(size) => size.to_string(),
+            None => String::from("NA"),
+        };
+        let family_sizes: Vec<String> = self
+            .family_sizes
+            .iter()
+            .map(|(size, count)| format!("{}:{}", size, count))
+            .collect();
+        write!(
+            fmt,
+            "\nDuplication Rate: {:.4}\nEstimated Library Size: {}\nFamily Sizes: {}",
+            self.duplication_rate(),
+            library_size,
+            family_sizes.join(" ")
+        )
+    }
+}
+
+/// The errors that can occur while grouping or deduplicating reads. Errors tied to a read carry
+/// its name.
+#[derive(Debug)]
+pub enum RumiError {
+    /// An error reading or writing a file.
+    Io(io::Error),
+    /// An error from htslib while reading or writing a bam.
+    Htslib(Error),
+    /// A read with no umi where one was expected.
+    MissingUmi { read: String },
+    /// A read whose umi can't be used.
+    InvalidUmi { read: String, umi: String },
+    /// A read that doesn't make sense, or is out of place in the input.
+    MalformedRecord { read: String, reason: String },
+    /// Settings that can't be used together, or can't be used at all.
+    InvalidConfig(String),
+    /// A thread processing the input panicked, with its panic message.
+    ThreadPanic(String),
+}
+
+impl RumiError {
+    fn missing_umi(record: &bam::record::Record) -> Self {
+        RumiError::MissingUmi {
+            read: read_name(record),
+        }
+    }
+
+    fn invalid_umi(record: &bam::record::Record, umi: &[u8]) -> Self {
+        RumiError::InvalidUmi {
+            read: read_name(record),
+            umi: String::from_utf8_lossy(umi).into_owned(),
+        }
+    }
+
+    fn malformed_record(record: &bam::record::Record, reason: &str) -> Self {
+        RumiError::MalformedRecord {
+            read: read_name(record),
+            reason: reason.to_string(),
+        }
+    }
+
+    fn thread_panic(panic: Box<dyn Any + Send>) -> Self {
+        let message = match panic.downcast_ref::<&str>() {
+            Some(message) => message.to_string(),
+            None => match panic.downcast_ref::<String>() {
+                Some(message) => message.clone(),
+                None => String::from("unknown panic"),
+            },
+        };
+        RumiError::ThreadPanic(message)
+    }
+}
+
+fn read_name(record: &bam::record::Record) -> String {
+    String::from_utf8_lossy(record.qname()).into_owned()
+}
+
+impl Display for RumiError {
+    fn fmt(&self, fmt: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            RumiError::Io(err) => write!(fmt, "IO error: {}", err),
+            RumiError::Htslib(err) => write!(fmt, "htslib error: {}", err),
+            RumiError::MissingUmi { read } => write!(fmt, "No umi found for read {}", read),
+            RumiError::InvalidUmi { read, umi } => {
+                write!(fmt, "Invalid umi {} found for read {}", umi, read)
+            }
+            RumiError::MalformedRecord { read, reason } => {
+                write!(fmt, "Malformed read {}: {}", read, reason)
+            }
+            RumiError::InvalidConfig(reason) => write!(fmt, "Invalid config: {}", reason),
+            RumiError::ThreadPanic(message) => {
+                write!(fmt, "Processing thread panicked: {}", message)
+            }
+        }
+    }
+}
+
+impl error::Error for RumiError {
+    fn source(&self) -> Option<&(dyn error::Error + 'static)> {
+        match self {
+            RumiError::Io(err) => Some(err),
+            RumiError::Htslib(err) => Some(err),
+            _ => None,
+        }
+    }
+}
+
+impl From<io::Error> for RumiError {
+    fn from(err: io::Error) -> Self {
+        RumiError::Io(err)
+    }
+}
+
+impl From<Error> for RumiError {
+    fn from(err: Error) -> Self {
+        RumiError::Htslib(err)
+    }
+}
+
+/// The counts of reads for a single umi across all positions, before and after grouping.
+#[derive(Debug, Default)]
+struct UmiCounts {
+    pre: Vec<u32>,
+    post: Vec<u32>,
+}
+
+/// Per position and per umi stats, mirroring the tables written by umi_tools --output-stats.
+/// Before grouping every distinct umi at a position is counted, and after grouping each group is
+/// counted as its master umi with all the reads of the group.
+#[derive(Debug, Default)]
+pub struct UmiStats {
+    /// For each number of reads, how many umis at a position had that many reads.
+    counts_pre: BTreeMap<u32, u64>,
+    counts_post: BTreeMap<u32, u64>,
+    per_umi: HashMap<Vec<u8>, UmiCounts>,
+    /// For each average edit distance between the umis at a position, how many positions had it.
+    /// None is for positions with a single umi.
+    edit_dist_pre: BTreeMap<Option<u32>, u64>,
+    edit_dist_post: BTreeMap<Option<u32>, u64>,
+    /// For each number of umis, how many positions had that many umis. Used for the null
+    /// edit distances.
+    umis_per_position_pre: BTreeMap<usize, u64>,
+    umis_per_position_post: BTreeMap<usize, u64>,
+    /// For each group size, how many groups had that many umis, and that many reads.
+    group_umis: BTreeMap<usize, u64>,
+    group_reads: BTreeMap<usize, u64>,
+}
+
+/// The seed for sampling the umis used for the null edit distances, so that reruns match.
+const NULL_SEED: u64 = 0x2545_F491_4F6C_DD1D;
+
+impl UmiStats {
+    pub fn new() -> Self {
+        UmiStats::default()
+    }
+
+    /// Add the umis at a position, and the groups they were put in.
+    fn add_position(&mut self, graph: &[Node], groups: &[Group]) {
+        for node in graph.iter() {
+            *self.counts_pre.entry(node.freq.freq).or_insert(0) += 1;
+            self.per_umi
+                .entry(node.umi.decode())
+                .or_default()
+                .pre
+                .push(node.freq.freq);
+        }
+        *self.umis_per_position_pre.entry(graph.len()).or_insert(0) += 1;
+        let dist = average_distance(&graph.iter().map(|n| n.umi).collect::<Vec<_>>());
+        *self.edit_dist_pre.entry(dist).or_insert(0) += 1;
+
+        for group in groups.iter() {
+            let count: u32 = group.nodes.iter().map(|n| n.freq.freq).sum();
+            *self.counts_post.entry(count).or_insert(0) += 1;
+            self.per_umi
+                .entry(group.umi.decode())
+                .or_default()
+                .post
+                .push(count);
+            *self.group_umis.entry(group.nodes.len()).or_insert(0) += 1;
+            *self.group_reads.entry(count as usize).or_insert(0) += 1;
+        }
+        if !groups.is_empty() {
+            *self.umis_per_position_post.entry(groups.len()).or_insert(0) += 1;
+            let dist = average_distance(&groups.iter().map(|g| *g.umi).collect::<Vec<_>>());
+            *self.edit_dist_post.entry(dist).or_insert(0) += 1;
+        }
+    }
+
+    /// Add the stats from another set of positions.
+    pub fn update(&mut self, other: UmiStats) {
+        fn merge<K: Ord>(a: &mut BTreeMap<K, u64>, b: BTreeMap<K, u64>) {
+            for (key, count) in b.into_iter() {
+                *a.entry(key).or_insert(0) += count;
+            }
+        }
+        merge(&mut self.counts_pre, other.counts_pre);
+        merge(&mut self.counts_post, other.counts_post);
+        merge(&mut self.edit_dist_pre, other.edit_dist_pre);
+        merge(&mut self.edit_dist_post, other.edit_dist_post);
+        merge(&mut self.umis_per_position_pre, other.umis_per_position_pre);
+        merge(
+            &mut self.umis_per_position_post,
+            other.umis_per_position_post,
+        );
+        merge(&mut self.group_umis, other.group_umis);
+        merge(&mut self.group_reads, other.group_reads);
+        for (umi, counts) in other.per_umi.into_iter() {
+            let entry = self.per_umi.entry(umi).or_default();
+            entry.pre.extend(counts.pre);
+            entry.post.extend(counts.post);
+        }
+    }
+
+    /// The expected average edit distances if the umis at each position were drawn at random from
+    /// all the umis seen, weighted by their read counts.
+    fn null_edit_dist(
+        &self,
+        umis_per_position: &BTreeMap<usize, u64>,
+    ) -> BTreeMap<Option<u32>, u64> {
+        let mut umis = vec![];
+        let mut cumulative = vec![];
+        let mut total: u64 = 0;
+        let mut sorted: Vec<(&Vec<u8>, &UmiCounts)> = self.per_umi.iter().collect();
+        sorted.sort_by_key(|(umi, _)| *umi);
+        for (umi, counts) in sorted.into_iter() {
+            let reads: u64 = counts.pre.iter().map(|&c| u64::from(c)).sum();
+            if reads == 0 {
+                continue;
+            }
+            total += reads;
+            umis.push(BaseBits::new(umi).unwrap());
+            cumulative.push(total);
+        }
+
+        let mut rng = XorShift(NULL_SEED);
+        let mut null = BTreeMap::new();
+        for (&num_umis, &positions) in umis_per_position.iter() {
+            for _ in 0..positions {
+                let sample: Vec<BaseBits> = (0..num_umis)
+                    .map(|_| {
+                        let pick = rng.next() % total;
+                        let i = match cumulative.binary_search(&(pick + 1)) {
+                            Ok(i) | Err(i) => i,
+                        };
+                        umis[i]
+                    })
+                    .collect();
+                *null.entry(average_distance(&sample)).or_insert(0) += 1;
+            }
+        }
+        null
+    }
+}
+
+/// The average hamming distance between every pair of umis, rounded down, or None if there are
+/// fewer than two.
+fn average_distance(umis: &[BaseBits]) -> Option<u32> {
+    if umis.len() < 2 {
+        return None;
+    }
+    let mut total: u64 = 0;
+    let mut pairs: u64 = 0;
+    for i in 0..umis.len() {
+        for j in (i + 1)..umis.len() {
+            total += u64::from(hamming_dist_none(&umis[i], &umis[j]));
+            pairs += 1;
+        }
+    }
+    Some((total / pairs) as u32)
+}
+
+/// A small xorshift generator, good enough for sampling the null umis.
+struct XorShift(u64);
+
+impl XorShift {
+    fn next(&mut self) -> u64 {
+        self.0 ^= self.0 << 13;
+        self.0 ^= self.0 >> 7;
+        self.0 ^= self.0 << 17;
+        self.0
     }
 }
 
+fn median(counts: &[u32]) -> f64 {
+    if counts.is_empty() {
+        return 0.0;
+    }
+    let mut counts = counts.to_vec();
+    counts.sort_unstable();
+    let mid = counts.len() / 2;
+    if counts.len() % 2 == 0 {
+        f64::from(counts[mid - 1] + counts[mid]) / 2.0
+    } else {
+        f64::from(counts[mid])
+    }
+}
+
+/// Write the umi_tools style stats tables, each to a file starting with prefix:
+/// - _per_umi_per_position.tsv: how many umis at a position had each number of reads
+/// - _per_umi.tsv: how often each umi was seen, and with how many reads
+/// - _edit_distance.tsv: the average edit distance between the umis at each position, against
+///   umis drawn at random
+/// - _group_sizes.tsv: how many groups had each number of umis and reads
+pub fn write_umi_stats(
+    umi_stats: &UmiStats,
+    config: &Config,
+    prefix: &str,
+) -> Result<(), RumiError> {
+    let create = |name: &str| -> Result<BufWriter<File>, io::Error> {
+        Ok(BufWriter::new(File::create(format!(
+            "{}_{}.tsv",
+            prefix, name
+        ))?))
+    };
+
+    let mut writer = create("per_umi_per_position")?;
+    writeln!(writer, "counts\tinstances_pre\tinstances_post")?;
+    let counts: BTreeSet<&u32> = umi_stats
+        .counts_pre
+        .keys()
+        .chain(umi_stats.counts_post.keys())
+        .collect();
+    for count in counts.into_iter() {
+        writeln!(
+            writer,
+            "{}\t{}\t{}",
+            count,
+            umi_stats.counts_pre.get(count).unwrap_or(&0),
+            umi_stats.counts_post.get(count).unwrap_or(&0)
+        )?;
+    }
+    writer.flush()?;
+
+    let mut writer = create("per_umi")?;
+    writeln!(writer, "UMI\ttimes_observed_pre\ttotal_counts_pre\tmedian_counts_pre\ttimes_observed_post\ttotal_counts_post\tmedian_counts_post")?;
+    let mut umis: Vec<(&Vec<u8>, &UmiCounts)> = umi_stats.per_umi.iter().collect();
+    umis.sort_by_key(|(umi, _)| *umi);
+    for (umi, counts) in umis.into_iter() {
+        writeln!(
+            writer,
+            "{}\t{}\t{}\t{}\t{}\t{}\t{}",
+            String::from_utf8_lossy(umi),
+            counts.pre.len(),
+            counts.pre.iter().map(|&c| u64::from(c)).sum::<u64>(),
+            median(&counts.pre),
+            counts.post.len(),
+            counts.post.iter().map(|&c| u64::from(c)).sum::<u64>(),
+            median(&counts.post)
+        )?;
+    }
+    writer.flush()?;
+
+    let mut writer = create("edit_distance")?;
+    let null_pre = umi_stats.null_edit_dist(&umi_stats.umis_per_position_pre);
+    let null_post = umi_stats.null_edit_dist(&umi_stats.umis_per_position_post);
+    let columns = [
+        &umi_stats.edit_dist_pre,
+        &null_pre,
+        &umi_stats.edit_dist_post,
+        &null_post,
+    ];
+    writeln!(
+        writer,
+        "edit_distance\tunique\tunique_null\t{}\t{}_null",
+        config.method, config.method
+    )?;
+    let max_dist = columns
+        .iter()
+        .filter_map(|c| c.keys().filter_map(|&d| d).max())
+        .max();
+    let mut rows: Vec<(String, Option<u32>)> = match max_dist {
+        Some(max_dist) => (0..=max_dist).map(|d| (d.to_string(), Some(d))).collect(),
+        None => vec![],
+    };
+    rows.push((String::from("Single_UMI"), None));
+    for (name, dist) in rows.into_iter() {
+        let counts: Vec<String> = columns
+            .iter()
+            .map(|c| c.get(&dist).unwrap_or(&0).to_string())
+            .collect();
+        writeln!(writer, "{}\t{}", name, counts.join("\t"))?;
+    }
+    writer.flush()?;
+
+    let mut writer = create("group_sizes")?;
+    writeln!(writer, "size\tgroups_by_umis\tgroups_by_reads")?;
+    let sizes: BTreeSet<&usize> = umi_stats
+        .group_umis
+        .keys()
+        .chain(umi_stats.group_reads.keys())
+        .collect();
+    for size in sizes.into_iter() {
+        writeln!(
+            writer,
+            "{}\t{}\t{}",
+            size,
+            umi_stats.group_umis.get(size).unwrap_or(&0),
+            umi_stats.group_reads.get(size).unwrap_or(&0)
+        )?;
+    }
+    writer.flush()?;
+    Ok(())
+}
+
 pub type UmiMap = HashMap<BaseBits, ReadFreq>;
 pub type ReadMap = BTreeMap<Position, UmiMap>;
 
 /// The main function to coordinate the deduplication process
-pub fn run_dedup(config: &Config) -> Result<(), &'static str> {
-    let mut bam = bam::Reader::from_path(&config.input_bam).unwrap();
+pub fn run_dedup(config: &Config) -> Result<(), RumiError> {
+    let bam = open_reader(&config.input_bam)?;
     let header = bam::Header::from_template(bam.header());
-    let mut writer = bam::Writer::from_path(&config.output_bam, &header, bam::Format::BAM).unwrap();
-    let mut read_store: HashSet<Vec<u8>> = HashSet::new();
-    let (sender, reciever) = channel();
+    let mut writer = open_writer(&config.output_bam, &header)?;
+    let mut discarded = open_discarded(config, &header)?;
     let global_stats = Arc::new(Mutex::new(Stats::new()));
+    let thread_stats = Arc::clone(&global_stats);
+    let global_umi_stats = Arc::new(Mutex::new(UmiStats::new()));
+    let thread_umi_stats = Arc::clone(&global_umi_stats);
 
-    let bundler = Bundler {
-        records: bam.records(),
-        last_chr: None,
-        next_bundle: vec![],
-    };
+    let mut reads_out = 0;
+    process_bundles(
+        bam,
+        config,
+        move |bundle, config| {
+            let (x, passthrough, mut discarded, mut stats) = group_reads(bundle, config)?;
+            let mut umi_stats = config.output_stats.as_ref().map(|_| UmiStats::new());
+            let reads = x
+                .into_iter()
+                .flat_map(|(position, reads)| {
+                    let (stats, umi_stats, discarded) =
+                        (&mut stats, &mut umi_stats, &mut discarded);
+                    if config.consensus {
+                        consensus(&position, reads, config, stats, umi_stats, discarded)
+                    } else if config.mark_duplicates {
+                        mark_duplicates(&position, reads, config, stats, umi_stats)
+                    } else {
+                        dedup(&position, reads, config, stats, umi_stats, discarded)
+                    }
+                })
+                .collect();
+            thread_stats.lock().unwrap().update(&stats);
+            if let Some(umi_stats) = umi_stats {
+                thread_umi_stats.lock().unwrap().update(umi_stats);
+            }
+            Ok((reads, passthrough, discarded))
+        },
+        |read, output| write_read(read, output, &mut writer, &mut discarded, &mut reads_out),
+    )?;
 
-    bundler
-        .par_bridge()
-        .flat_map(|bundle| {
-            let (x, stats) = group_reads(bundle, &config);
-            let y: Vec<(Position, UmiMap)> = x.into_iter().collect();
-            let mut g_stats = global_stats.lock().unwrap();
-            g_stats.update(&stats);
-            y
-        })
-        .flat_map(|(_, reads)| dedup(reads, config))
-        .for_each_with(sender, |s, x| s.send(x).unwrap());
+    let mut stats = global_stats.lock().unwrap();
+    stats.reads_out = reads_out;
+    eprintln!("{}", stats);
+    if let Some(prefix) = &config.output_stats {
+        write_umi_stats(&global_umi_stats.lock().unwrap(), config, prefix)?;
+    }
+    write_stats(&stats, config)
+}
+
+pub fn run_group(config: &Config) -> Result<(), RumiError> {
+    let bam = open_reader(&config.input_bam)?;
+    let header = bam::Header::from_template(bam.header());
+    let mut writer = open_writer(&config.output_bam, &header)?;
+    let mut discarded = open_discarded(config, &header)?;
+    let global_stats = Arc::new(Mutex::new(Stats::new()));
+    let thread_stats = Arc::clone(&global_stats);
+    let global_umi_stats = Arc::new(Mutex::new(UmiStats::new()));
+    let thread_umi_stats = Arc::clone(&global_umi_stats);
 
     let mut reads_out = 0;
-    reciever.iter().for_each(|read| {
-        reads_out += 1;
-        writer.write(&read).unwrap_or_else(|err| {
-            eprintln!("Problem writing: {}", err);
-            process::exit(1);
-        });
-        if config.is_paired {
-            read_store.insert(read.qname().to_vec());
+    process_bundles(
+        bam,
+        config,
+        move |bundle, config| {
+            let (x, passthrough, mut discarded, mut stats) = group_reads(bundle, config)?;
+            let mut umi_stats = config.output_stats.as_ref().map(|_| UmiStats::new());
+            let reads = x
+                .into_iter()
+                .flat_map(|(position, reads)| {
+                    label_groups(
+                        &position,
+                        reads,
+                        config,
+                        &mut stats,
+                        &mut umi_stats,
+                        &mut discarded,
+                    )
+                })
+                .flatten()
+                .collect();
+            thread_stats.lock().unwrap().update(&stats);
+            if let Some(umi_stats) = umi_stats {
+                thread_umi_stats.lock().unwrap().update(umi_stats);
+            }
+            Ok((reads, passthrough, discarded))
+        },
+        |read, output| write_read(read, output, &mut writer, &mut discarded, &mut reads_out),
+    )?;
+
+    let mut stats = global_stats.lock().unwrap();
+    stats.reads_out = reads_out;
+    eprintln!("{}", stats);
+    if let Some(prefix) = &config.output_stats {
+        write_umi_stats(&global_umi_stats.lock().unwrap(), config, prefix)?;
+    }
+    write_stats(&stats, config)
+}
+
+/// The number of molecules of each gene in each cell. Without --per_cell the cell is empty.
+pub type GeneCounts = BTreeMap<(Vec<u8>, Vec<u8>), u32>;
+
+/// Count the molecules of each gene, in each cell with --per_cell, like umi_tools count, and
+/// write the counts instead of a bam. Reads are always grouped per gene, and each group is one
+/// molecule.
+pub fn run_count(config: &Config) -> Result<(), RumiError> {
+    let config = Config {
+        per_gene: true,
+        ..config.clone()
+    };
+    let mut bam = open_reader(&config.input_bam)?;
+    let bundles: Result<Vec<(GeneCounts, Stats)>, RumiError> =
+        Bundler::new(bam.records(), i32::MAX)
+            .par_bridge()
+            .map(|bundle| count_molecules(bundle?.records, &config))
+            .collect();
+
+    let mut counts = GeneCounts::new();
+    let mut stats = Stats::new();
+    for (bundle_counts, bundle_stats) in bundles?.into_iter() {
+        // A gene id can turn up on more than one reference
+        for (key, count) in bundle_counts.into_iter() {
+            *counts.entry(key).or_insert(0) += count;
         }
-    });
+        stats.update(&bundle_stats);
+    }
+    write_counts(&counts, &config)?;
+    eprintln!("{}", stats);
+    write_stats(&stats, &config)
+}
 
-    if config.is_paired {
-        bam.records()
-            .map(|read| read.unwrap())
-            .filter(|read| read.is_last_in_template() && read_store.contains(read.qname()))
-            .for_each(|read| {
-                reads_out += 1;
-                writer.write(&read).unwrap_or_else(|err| {
-                    eprintln!("Problem writing: {}", err);
-                    process::exit(1);
-                });
-            });
+/// Group the reads of a bundle per gene, and count the groups of each gene and cell.
+pub fn count_molecules(
+    bundle: Vec<bam::record::Record>,
+    config: &Config,
+) -> Result<(GeneCounts, Stats), RumiError> {
+    let (grouped, _, _, mut stats) = group_reads(bundle, config)?;
+    let mut counts = GeneCounts::new();
+    for (position, reads) in grouped.into_iter() {
+        let graph = build_network(reads, config);
+        let groups = find_groups(&graph, config);
+        stats.add_groups(&groups);
+        let key = (
+            position.gene.unwrap_or_default(),
+            position.cell.unwrap_or_default(),
+        );
+        *counts.entry(key).or_insert(0) += groups.len() as u32;
     }
+    Ok((counts, stats))
+}
 
-    let mut stats = global_stats.lock().unwrap();
-    stats.reads_out += reads_out;
-    println!("{}", stats);
+/// Write the counts in the configured format. A tsv is written to the output, or stdout if it's
+/// -, with a gene, cell if counting per cell, and count per line. A Matrix Market matrix is
+/// written to matrix.mtx in the output directory, with a line per cell in barcodes.tsv and per
+/// gene in features.tsv, laid out like the matrices of Cell Ranger so they read in the same way.
+pub fn write_counts(counts: &GeneCounts, config: &Config) -> Result<(), RumiError> {
+    match config.count_format {
+        CountFormat::Tsv => {
+            let writer: Box<dyn Write> = if config.count_output == "-" {
+                Box::new(io::stdout())
+            } else {
+                Box::new(File::create(&config.count_output)?)
+            };
+            let mut writer = BufWriter::new(writer);
+            if config.per_cell {
+                writeln!(writer, "gene\tcell\tcount")?;
+            } else {
+                writeln!(writer, "gene\tcount")?;
+            }
+            for ((gene, cell), count) in counts.iter() {
+                let gene = String::from_utf8_lossy(gene);
+                if config.per_cell {
+                    writeln!(
+                        writer,
+                        "{}\t{}\t{}",
+                        gene,
+                        String::from_utf8_lossy(cell),
+                        count
+                    )?;
+                } else {
+                    writeln!(writer, "{}\t{}", gene, count)?;
+                }
+            }
+            writer.flush()?;
+        }
+        CountFormat::Mtx => {
+            let dir = std::path::Path::new(&config.count_output);
+            std::fs::create_dir_all(dir)?;
+            let create = |name: &str| -> Result<BufWriter<File>, io::Error> {
+                Ok(BufWriter::new(File::create(dir.join(name))?))
+            };
+            let genes: BTreeSet<&Vec<u8>> = counts.keys().map(|(gene, _)| gene).collect();
+            let cells: BTreeSet<&Vec<u8>> = counts.keys().map(|(_, cell)| cell).collect();
+
+            let mut writer = create("features.tsv")?;
+            // The tags only hold the gene id, so it's used for the name as well
+            for gene in genes.iter() {
+                let gene = String::from_utf8_lossy(gene);
+                writeln!(writer, "{}\t{}\tGene Expression", gene, gene)?;
+            }
+            writer.flush()?;
+
+            let mut writer = create("barcodes.tsv")?;
+            for cell in cells.iter() {
+                writeln!(writer, "{}", String::from_utf8_lossy(cell))?;
+            }
+            writer.flush()?;
+
+            // Rows and columns are numbered from 1 in the order of the features and barcodes
+            fn numbered<'a>(names: &BTreeSet<&'a Vec<u8>>) -> HashMap<&'a Vec<u8>, usize> {
+                names
+                    .iter()
+                    .enumerate()
+                    .map(|(i, &name)| (name, i + 1))
+                    .collect()
+            }
+            let (gene_rows, cell_cols) = (numbered(&genes), numbered(&cells));
+            let mut writer = create("matrix.mtx")?;
+            writeln!(writer, "%%MatrixMarket matrix coordinate integer general")?;
+            writeln!(writer, "{} {} {}", genes.len(), cells.len(), counts.len())?;
+            for ((gene, cell), count) in counts.iter() {
+                writeln!(writer, "{} {} {}", gene_rows[gene], cell_cols[cell], count)?;
+            }
+            writer.flush()?;
+        }
+    }
     Ok(())
 }
 
-pub fn run_group(config: &Config) -> Result<(), &'static str> {
-    let mut bam = bam::Reader::from_path(&config.input_bam).unwrap();
-    let header = bam::Header::from_template(bam.header());
-    let mut writer = bam::Writer::from_path(&config.output_bam, &header, bam::Format::BAM).unwrap();
-    let mut read_store: HashMap<Vec<u8>, (bam::record::Aux, Vec<u8>)> = HashMap::new();
-    let global_stats = Arc::new(Mutex::new(Stats::new()));
-    let (sender, reciever) = channel();
+/// Open the bam to write discarded reads to, if there is one.
+fn open_discarded(config: &Config, header: &bam::Header) -> Result<Option<bam::Writer>, RumiError> {
+    match &config.discarded {
+        Some(path) => Ok(Some(open_writer(path, header)?)),
+        None => Ok(None),
+    }
+}
 
-    let bundler = Bundler {
-        records: bam.records(),
-        last_chr: None,
-        next_bundle: vec![],
-    };
+/// Write a read to the output, or to the discarded bam if it was discarded, counting the reads
+/// written to the output.
+fn write_read(
+    read: bam::record::Record,
+    output: Output,
+    writer: &mut bam::Writer,
+    discarded: &mut Option<bam::Writer>,
+    reads_out: &mut u32,
+) -> Result<(), RumiError> {
+    match (output, discarded) {
+        (Output::Discarded, Some(discarded)) => discarded.write(&read)?,
+        (Output::Discarded, None) => {}
+        _ => {
+            *reads_out += 1;
+            writer.write(&read)?;
+        }
+    }
+    Ok(())
+}
+
+/// Open a bam to read, or stdin if the path is -.
+fn open_reader(path: &str) -> Result<bam::Reader, RumiError> {
+    if path == "-" {
+        Ok(bam::Reader::from_stdin()?)
+    } else {
+        Ok(bam::Reader::from_path(path)?)
+    }
+}
 
-    bundler
-        .par_bridge()
-        .flat_map(|bundle| {
-            let (x, stats) = group_reads(bundle, &config);
-            let y: Vec<(Position, UmiMap)> = x.into_iter().collect();
-            let mut g_stats = global_stats.lock().unwrap();
-            g_stats.update(&stats);
-            y
+/// Open a bam to write, or stdout if the path is -.
+fn open_writer(path: &str, header: &bam::Header) -> Result<bam::Writer, RumiError> {
+    if path == "-" {
+        Ok(bam::Writer::from_stdout(header, bam::Format::BAM)?)
+    } else {
+        Ok(bam::Writer::from_path(path, header, bam::Format::BAM)?)
+    }
+}
+
+/// The grouped reads from a bundle, the reads to pass through untouched, and the discarded reads.
+/// When paired, the passed through reads include the mates, which are written with the read they
+/// are paired with.
+type ProcessedReads = (
+    Vec<bam::record::Record>,
+    Vec<bam::record::Record>,
+    Vec<bam::record::Record>,
+);
+
+/// Where a processed read is written.
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Output {
+    /// A read kept for, or labeled with, its group
+    Grouped,
+    /// A read written untouched
+    Passthrough,
+    /// A read for the discarded bam
+    Discarded,
+}
+
+/// A mate, or an alignment following its primary, is written where its read is, but untouched
+/// unless its read is discarded.
+fn follow_output(output: Output) -> Output {
+    match output {
+        Output::Discarded => Output::Discarded,
+        _ => Output::Passthrough,
+    }
+}
+
+/// The number of processed bundles that can be waiting to be written before processing blocks.
+const BUNDLE_QUEUE_SIZE: usize = 1024;
+
+/// Split the reads in bam into bundles, process the bundles in parallel, and pass every read that
+/// process returns to write. Process returns the grouped, passed through and discarded reads,
+/// and write is told which of the three each read is. Reads are passed to write in
+/// coordinate order unless
+/// config.unordered is set, in which case they are written as soon as their bundle is done.
+/// When paired, mates go through a MateBuffer so they are only written along with their read.
+/// Stops at the first error from reading, processing or writing, or if processing panics, and
+/// always waits for the processing thread to stop before returning.
+fn process_bundles<P, W>(
+    bam: bam::Reader,
+    config: &Config,
+    process: P,
+    mut write: W,
+) -> Result<(), RumiError>
+where
+    P: Fn(Vec<bam::record::Record>, &Config) -> Result<ProcessedReads, RumiError>
+        + Send
+        + Sync
+        + 'static,
+    W: FnMut(bam::record::Record, Output) -> Result<(), RumiError>,
+{
+    let mut sorter = CoordinateSorter::new();
+    let mut released = ReleaseTracker::new();
+    let mut mates = MateBuffer::new(config, bam.header());
+    let (sender, reciever) = sync_channel(BUNDLE_QUEUE_SIZE);
+    let thread_config = config.clone();
+    let producer = thread::spawn(move || {
+        let mut bam = bam;
+        // Sending only fails once the reciever has hit an error and hung up
+        // A gene's reads can start anywhere in it, so with per_gene each reference is a bundle
+        let window = if thread_config.per_gene {
+            i32::MAX
+        } else {
+            thread_config.bundle_window
+        };
+        let _ = Bundler::new(bam.records(), window)
+            .enumerate()
+            .par_bridge()
+            .map(|(i, bundle)| -> Result<(usize, Bundle), RumiError> {
+                let bundle = bundle?;
+                let (records, passthrough, discarded) = process(bundle.records, &thread_config)?;
+                Ok((
+                    i,
+                    Bundle {
+                        records,
+                        passthrough,
+                        discarded,
+                        released_before: bundle.released_before,
+                    },
+                ))
+            })
+            .try_for_each_with(sender, |s, x| s.send(x));
+    });
+
+    let written: Result<(), RumiError> = reciever
+        .iter()
+        .try_for_each(|result| {
+            let (i, bundle) = result?;
+            let ready = if config.unordered {
+                released.push(i, bundle.released_before);
+                bundle.reads().collect()
+            } else {
+                sorter.push(i, bundle)
+            };
+            for (read, output) in ready.into_iter() {
+                mates.push(read, output);
+            }
+            if config.unordered {
+                mates.expire(released.released_before);
+            } else {
+                mates.expire(sorter.released_before);
+            }
+            for (read, output) in mates.ready() {
+                write(read, output)?;
+            }
+            Ok(())
         })
-        .flat_map(|(_, reads)| label_groups(reads, config))
-        .for_each_with(sender, |s, x| s.send(x).unwrap());
+        .and_then(|()| {
+            for (read, output) in sorter.finish().into_iter() {
+                mates.push(read, output);
+            }
+            for (read, output) in mates.finish() {
+                write(read, output)?;
+            }
+            Ok(())
+        });
+    // Hang up first, so that on an error the producer stops at its next send instead of waiting
+    // on a full queue
+    drop(reciever);
+    let joined = producer.join();
+    written?;
+    joined.map_err(RumiError::thread_panic)
+}
 
-    let mut group_count: i64 = 0;
-    let mut reads_out = 0;
-    reciever.iter().for_each(|mut group| {
-        for read in group.iter_mut() {
-            reads_out += 1;
-            read.push_aux(b"UG", &bam::record::Aux::Integer(group_count));
-            writer.write(&read).unwrap_or_else(|err| {
-                eprintln!("Problem writing: {}", err);
-                process::exit(1);
-            });
-            if config.is_paired {
-                let umi = read.aux(b"BX").unwrap().string().to_vec();
-                read_store.insert(
-                    read.qname().to_vec(),
-                    (bam::record::Aux::Integer(group_count), umi),
-                );
+/// The key reads are sorted by in a coordinate sorted bam. Unmapped reads without a tid of -1 sort
+/// after all others.
+type SortKey = (u32, i32);
+
+fn sort_key(record: &bam::record::Record) -> SortKey {
+    (record.tid() as u32, record.pos())
+}
+
+/// A read waiting to be written in coordinate order. Reads at the same coordinate keep the order
+/// they were added in.
+struct SortedRecord {
+    key: SortKey,
+    order: u64,
+    record: bam::record::Record,
+    output: Output,
+}
+
+impl PartialEq for SortedRecord {
+    fn eq(&self, other: &SortedRecord) -> bool {
+        self.key == other.key && self.order == other.order
+    }
+}
+
+impl Eq for SortedRecord {}
+
+impl PartialOrd for SortedRecord {
+    fn partial_cmp(&self, other: &SortedRecord) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl Ord for SortedRecord {
+    fn cmp(&self, other: &SortedRecord) -> Ordering {
+        self.key
+            .cmp(&other.key)
+            .then_with(|| self.order.cmp(&other.order))
+    }
+}
+
+/// Puts the reads from bundles that finished processing out of order back into coordinate order.
+/// Bundles are taken in the order the Bundler made them, and reads are held until every read
+/// before them in the input has been released in a bundle.
+struct CoordinateSorter {
+    next_bundle: usize,
+    pending: HashMap<usize, Bundle>,
+    heap: BinaryHeap<Reverse<SortedRecord>>,
+    order: u64,
+    /// Every read before this has been returned
+    released_before: SortKey,
+}
+
+impl CoordinateSorter {
+    fn new() -> Self {
+        CoordinateSorter {
+            next_bundle: 0,
+            pending: HashMap::new(),
+            heap: BinaryHeap::new(),
+            order: 0,
+            released_before: INPUT_START,
+        }
+    }
+
+    /// Add the i'th bundle made by the Bundler, returning any reads that are now ready to be
+    /// written, in order, along with where they are written.
+    fn push(&mut self, i: usize, bundle: Bundle) -> Vec<(bam::record::Record, Output)> {
+        self.pending.insert(i, bundle);
+        let mut ready = vec![];
+        while let Some(bundle) = self.pending.remove(&self.next_bundle) {
+            self.next_bundle += 1;
+            let released_before = bundle.released_before;
+            for (record, output) in bundle.reads() {
+                self.order += 1;
+                self.heap.push(Reverse(SortedRecord {
+                    key: sort_key(&record),
+                    order: self.order,
+                    record,
+                    output,
+                }));
             }
-            group_count += 1;
+            while let Some(Reverse(next)) = self.heap.peek() {
+                if next.key >= released_before {
+                    break;
+                }
+                let next = self.heap.pop().unwrap().0;
+                ready.push((next.record, next.output));
+            }
+            self.released_before = released_before;
         }
-    });
+        ready
+    }
 
-    if config.is_paired {
-        bam.records()
-            .map(|read| read.unwrap())
-            .filter(|read| read.is_last_in_template())
-            .for_each(|mut read| {
-                if let Some((ug, bx_val)) = read_store.get(read.qname()) {
-                    reads_out += 1;
-                    read.push_aux(b"UG", ug);
-                    read.push_aux(b"BX", &bam::record::Aux::String(&bx_val));
-                    writer.write(&read).unwrap_or_else(|err| {
-                        eprintln!("Problem writing: {}", err);
-                        process::exit(1);
-                    });
+    /// Return all remaining reads, in order.
+    fn finish(mut self) -> Vec<(bam::record::Record, Output)> {
+        let mut ready = vec![];
+        while let Some(Reverse(next)) = self.heap.pop() {
+            ready.push((next.record, next.output));
+        }
+        ready
+    }
+}
+
+/// Tracks how far the input has been released when bundles are written as soon as they are done,
+/// in any order. Every read before released_before is in a bundle that has been pushed.
+struct ReleaseTracker {
+    next_bundle: usize,
+    pending: HashMap<usize, SortKey>,
+    released_before: SortKey,
+}
+
+impl ReleaseTracker {
+    fn new() -> Self {
+        ReleaseTracker {
+            next_bundle: 0,
+            pending: HashMap::new(),
+            released_before: INPUT_START,
+        }
+    }
+
+    /// Note that the i'th bundle made by the Bundler, released up to released_before, is done.
+    fn push(&mut self, i: usize, released_before: SortKey) {
+        self.pending.insert(i, released_before);
+        while let Some(released_before) = self.pending.remove(&self.next_bundle) {
+            self.next_bundle += 1;
+            self.released_before = released_before;
+        }
+    }
+}
+
+/// Where a slot in the MateBuffer queue is at.
+enum Slot {
+    Ready(bam::record::Record, Output),
+    Waiting,
+    Dropped,
+}
+
+/// The position of the mate of a read, in the same order as sort_key.
+fn mate_key(record: &bam::record::Record) -> SortKey {
+    (record.mtid() as u32, record.mpos())
+}
+
+/// A key before every read in the input.
+const INPUT_START: SortKey = (0, i32::MIN);
+/// A key after every read in the input.
+const INPUT_END: SortKey = (u32::MAX, i32::MAX);
+
+/// How far past a read the alignments that follow it are looked for when where they are isn't
+/// known. Secondary alignments don't say where their primary is, so this bounds how long they are
+/// held waiting for it, and how long a written read is remembered for the alignments after it.
+const FOLLOW_WINDOW: i32 = 1_000_000;
+
+/// The key FOLLOW_WINDOW past a read. This can be past the end of its reference, in which case
+/// anything waiting on it waits until the next reference starts.
+fn follow_key(record: &bam::record::Record) -> SortKey {
+    let (tid, pos) = sort_key(record);
+    (tid, pos.saturating_add(FOLLOW_WINDOW))
+}
+
+/// Qnames waiting on something at a position, soonest first.
+type Expiry = BinaryHeap<Reverse<(SortKey, Vec<u8>)>>;
+
+/// Take the qnames waiting on anything before released_before, along with what they waited on.
+fn pop_expired(expiry: &mut Expiry, released_before: SortKey) -> Vec<(SortKey, Vec<u8>)> {
+    let mut expired = vec![];
+    while let Some(Reverse((key, _))) = expiry.peek() {
+        if *key >= released_before {
+            break;
+        }
+        expired.push(expiry.pop().unwrap().0);
+    }
+    expired
+}
+
+/// Holds the mates of paired reads until the read they are paired with is written, so that mates
+/// are written with the tags of their read, or not at all. Only the first read of a pair is
+/// grouped, and the second read is its mate.
+///
+/// Reads come out in the order they are pushed, so a mate that comes before its read holds back
+/// every read after it. Once the input has been released past where a mate's read is without the
+/// read being written, the read was dropped and so is the mate. Reads whose mate never turns up
+/// are forgotten in the same way. When unordered, mates are written as soon as their read is, and
+/// are given up on once every bundle up to where their read is has been pushed.
+///
+/// Secondary and supplementary alignments that follow their primary are held the same way, until
+/// the read that decides their template is written, which is the first read of the pair when
+/// paired. Supplementary alignments find where it is from their SA tag, and the mate's from
+/// their mate position, but other secondary alignments can't, so they wait for it for
+/// FOLLOW_WINDOW, and are dropped if it isn't written by then. Every decided read is remembered
+/// for FOLLOW_WINDOW, or until the input is past the alignments in its SA tag, so alignments
+/// after it follow it as soon as they are pushed.
+///
+/// When marking duplicates every read is written, so mates and alignments that are given up on
+/// are written unmarked instead of dropped.
+struct MateBuffer {
+    paired: bool,
+    ordered: bool,
+    /// Whether to copy the duplicate flag from a read to its mate
+    mark_duplicates: bool,
+    follow_secondary: bool,
+    follow_supplementary: bool,
+    /// The tid of each reference by name, to find alignments in SA tags
+    tids: HashMap<Vec<u8>, u32>,
+    /// The tags to copy from a read to its mate
+    tags: Vec<Vec<u8>>,
+    queue: VecDeque<Slot>,
+    /// The number of slots that have been taken off the front of the queue
+    popped: usize,
+    /// Mates waiting on their read by qname, and their place in the queue when ordered
+    mates: HashMap<Vec<u8>, (bam::record::Record, usize)>,
+    /// Reads that have been written and are waiting on their mate by qname, and where they were
+    /// written. None if the read was passed through, since its mate is passed through as well.
+    reads: HashMap<Vec<u8>, (Option<bam::record::Record>, Output)>,
+    /// When to give up on the waiting reads and mates, by the position of what they wait on
+    expiry: Expiry,
+    /// Alignments waiting on the read that decides their template by qname, with their place in
+    /// the queue and the position of that read
+    followers: HashMap<Vec<u8>, Vec<(bam::record::Record, usize, SortKey)>>,
+    follower_expiry: Expiry,
+    /// Decided reads that alignments may still follow by qname, with the read to tag them from,
+    /// None if there is nothing to tag them with, where it was written, and the position of the
+    /// last alignment that may follow
+    kept: HashMap<Vec<u8>, (Option<bam::record::Record>, Output, SortKey)>,
+    kept_expiry: Expiry,
+}
+
+impl MateBuffer {
+    fn new(config: &Config, header: &bam::HeaderView) -> Self {
+        let group_tags = vec![
+            &config.group_tag,
+            &config.corrected_umi_tag,
+            &config.original_umi_tag,
+        ];
+        let family_tags = config
+            .family_size_tag
+            .iter()
+            .chain(config.umi_count_tag.iter());
+        let mut tags: Vec<&String> = if config.group_only {
+            group_tags
+        } else if config.mark_duplicates {
+            group_tags.into_iter().chain(family_tags).collect()
+        } else {
+            family_tags.collect()
+        };
+        if config.discarded.is_some() {
+            if !config.group_only && !config.mark_duplicates {
+                tags.push(&config.group_tag);
+            }
+            tags.push(&config.discard_tag);
+        }
+        // The corrected cell barcode replaces whatever the mate came with
+        if config.whitelist.is_some() {
+            tags.push(&config.cell_tag);
+        }
+        MateBuffer {
+            paired: config.is_paired,
+            ordered: !config.unordered,
+            mark_duplicates: config.mark_duplicates,
+            follow_secondary: config.secondary == AlignmentPolicy::Follow,
+            follow_supplementary: config.supplementary == AlignmentPolicy::Follow,
+            tids: header
+                .target_names()
+                .into_iter()
+                .enumerate()
+                .map(|(tid, name)| (name.to_vec(), tid as u32))
+                .collect(),
+            tags: tags
+                .into_iter()
+                .map(|tag| tag.as_bytes().to_vec())
+                .collect(),
+            queue: VecDeque::new(),
+            popped: 0,
+            mates: HashMap::new(),
+            reads: HashMap::new(),
+            expiry: BinaryHeap::new(),
+            followers: HashMap::new(),
+            follower_expiry: BinaryHeap::new(),
+            kept: HashMap::new(),
+            kept_expiry: BinaryHeap::new(),
+        }
+    }
+
+    fn push(&mut self, record: bam::record::Record, output: Output) {
+        if record.is_secondary() || record.is_supplementary() {
+            let follow = if record.is_secondary() {
+                self.follow_secondary
+            } else {
+                self.follow_supplementary
+            };
+            if follow {
+                self.follow(record);
+            } else {
+                self.queue.push_back(Slot::Ready(record, output));
+            }
+            return;
+        }
+        let read = if output == Output::Passthrough {
+            None
+        } else {
+            Some(&record)
+        };
+        // Both reads of chimeric pairs are grouped, so neither waits on the other, and mates that
+        // are discarded without their read have nothing to wait on
+        if !self.paired
+            || record.tid() != record.mtid()
+            || (record.is_last_in_template() && output == Output::Discarded)
+        {
+            self.decided(&record, read, output);
+            self.queue.push_back(Slot::Ready(record, output));
+            return;
+        }
+        let qname = record.qname().to_vec();
+        if record.is_last_in_template() {
+            match self.reads.remove(&qname) {
+                Some((read, output)) => {
+                    let mate = self.tag_mate(record, read.as_ref());
+                    self.decided(&mate, read.as_ref(), output);
+                    self.queue
+                        .push_back(Slot::Ready(mate, follow_output(output)));
+                }
+                None => {
+                    let slot = self.popped + self.queue.len();
+                    if self.ordered {
+                        self.queue.push_back(Slot::Waiting);
+                    }
+                    self.expiry
+                        .push(Reverse((mate_key(&record), qname.clone())));
+                    self.mates.insert(qname, (record, slot));
                 }
-            });
+            }
+            return;
+        }
+
+        match self.mates.remove(&qname) {
+            Some((mate, slot)) => {
+                let mate = self.tag_mate(mate, read);
+                self.decided(&mate, read, output);
+                self.place(slot, Slot::Ready(mate, follow_output(output)));
+            }
+            None => {
+                self.expiry
+                    .push(Reverse((mate_key(&record), qname.clone())));
+                self.reads.insert(qname, (read.cloned(), output));
+            }
+        }
+        self.decided(&record, read, output);
+        self.queue.push_back(Slot::Ready(record, output));
+    }
+
+    /// Write a secondary or supplementary alignment if the read that decides its template has
+    /// been written, or hold it until that read is.
+    fn follow(&mut self, record: bam::record::Record) {
+        let qname = record.qname().to_vec();
+        let read = match self.reads.get(&qname) {
+            Some((read, output)) => Some((read, *output)),
+            None => self
+                .kept
+                .get(&qname)
+                .map(|(read, output, _)| (read, *output)),
+        };
+        if let Some((read, output)) = read {
+            let record = self.tag_mate(record, read.as_ref());
+            self.queue
+                .push_back(Slot::Ready(record, follow_output(output)));
+            return;
+        }
+
+        let until = if self.paired && record.is_last_in_template() {
+            mate_key(&record)
+        } else if record.is_supplementary() {
+            // The first alignment in the SA tag of a supplementary alignment is its primary
+            match record.aux(b"SA") {
+                Some(Aux::String(sa)) => self
+                    .sa_keys(sa)
+                    .first()
+                    .cloned()
+                    .unwrap_or_else(|| follow_key(&record)),
+                _ => follow_key(&record),
+            }
+        } else {
+            follow_key(&record)
+        };
+        let slot = self.popped + self.queue.len();
+        if self.ordered {
+            self.queue.push_back(Slot::Waiting);
+        }
+        self.follower_expiry.push(Reverse((until, qname.clone())));
+        self.followers
+            .entry(qname)
+            .or_default()
+            .push((record, slot, until));
+    }
+
+    /// A read that decides its template has been written to output, tagged from read. Write the
+    /// alignments waiting on it, and remember it for those that come after.
+    fn decided(
+        &mut self,
+        record: &bam::record::Record,
+        read: Option<&bam::record::Record>,
+        output: Output,
+    ) {
+        if !self.follow_secondary && !self.follow_supplementary {
+            return;
+        }
+        let qname = record.qname().to_vec();
+        if let Some(followers) = self.followers.remove(&qname) {
+            for (follower, slot, _) in followers {
+                let follower = self.tag_mate(follower, read);
+                self.place(slot, Slot::Ready(follower, follow_output(output)));
+            }
+        }
+
+        let mut until = follow_key(record);
+        if self.follow_supplementary {
+            if let Some(Aux::String(sa)) = record.aux(b"SA") {
+                until = until.max(self.sa_keys(sa).into_iter().max().unwrap_or(until));
+            }
+        }
+        if let Some((_, _, kept_until)) = self.kept.get(&qname) {
+            until = until.max(*kept_until);
+        }
+        // Only keep a copy of the read if there is something to tag the alignments with
+        let read = if self.tags.is_empty() && !self.mark_duplicates {
+            None
+        } else {
+            read.cloned()
+        };
+        self.kept_expiry.push(Reverse((until, qname.clone())));
+        self.kept.insert(qname, (read, output, until));
+    }
+
+    /// The positions of the alignments in an SA tag, in the same order as sort_key. Alignments on
+    /// references that aren't in the header are left out.
+    fn sa_keys(&self, sa: &[u8]) -> Vec<SortKey> {
+        sa.split(|&c| c == b';')
+            .filter_map(|alignment| {
+                let mut fields = alignment.split(|&c| c == b',');
+                let tid = self.tids.get(fields.next()?)?;
+                let pos = fields.next()?;
+                if pos.is_empty() || !pos.iter().all(u8::is_ascii_digit) {
+                    return None;
+                }
+                let pos = pos.iter().fold(0, |pos, &c| pos * 10 + i32::from(c - b'0'));
+                Some((*tid, pos - 1))
+            })
+            .collect()
+    }
+
+    /// Give a mate the tags of its read, and its duplicate flag when marking duplicates.
+    fn tag_mate(
+        &self,
+        mut mate: bam::record::Record,
+        read: Option<&bam::record::Record>,
+    ) -> bam::record::Record {
+        if let Some(read) = read {
+            if self.mark_duplicates {
+                if read.is_duplicate() {
+                    mate.set_duplicate();
+                } else {
+                    mate.unset_duplicate();
+                }
+            }
+            for tag in self.tags.iter() {
+                if let Some(value) = read.aux(tag) {
+                    set_aux(&mut mate, tag, &value);
+                }
+            }
+        }
+        mate
+    }
+
+    /// Fill a mate's place in the queue, or add it to the end when unordered.
+    fn place(&mut self, slot: usize, value: Slot) {
+        if self.ordered {
+            self.queue[slot - self.popped] = value;
+        } else {
+            self.queue.push_back(value);
+        }
+    }
+
+    /// Drop a mate or alignment whose read was never written, or write it unmarked when marking
+    /// duplicates, since then every read is written.
+    fn give_up(&mut self, mut record: bam::record::Record, slot: usize) {
+        if self.mark_duplicates {
+            record.unset_duplicate();
+            self.place(slot, Slot::Ready(record, Output::Passthrough));
+        } else if self.ordered {
+            self.place(slot, Slot::Dropped);
+        }
+    }
+
+    /// Give up on the reads, mates and alignments waiting on anything before released_before,
+    /// which every read before has been pushed by.
+    fn expire(&mut self, released_before: SortKey) {
+        for (_, qname) in pop_expired(&mut self.expiry, released_before) {
+            self.reads.remove(&qname);
+            if let Some((mate, slot)) = self.mates.remove(&qname) {
+                self.give_up(mate, slot);
+            }
+        }
+        for (key, qname) in pop_expired(&mut self.follower_expiry, released_before) {
+            if let Some(followers) = self.followers.remove(&qname) {
+                let (expired, waiting): (Vec<_>, Vec<_>) = followers
+                    .into_iter()
+                    .partition(|(_, _, until)| *until <= key);
+                for (follower, slot, _) in expired {
+                    self.give_up(follower, slot);
+                }
+                if !waiting.is_empty() {
+                    self.followers.insert(qname, waiting);
+                }
+            }
+        }
+        for (key, qname) in pop_expired(&mut self.kept_expiry, released_before) {
+            if let Some((_, _, until)) = self.kept.get(&qname) {
+                if *until <= key {
+                    self.kept.remove(&qname);
+                }
+            }
+        }
+    }
+
+    /// Take the reads that are ready to be written, in order.
+    fn ready(&mut self) -> Vec<(bam::record::Record, Output)> {
+        let mut ready = vec![];
+        while let Some(slot) = self.queue.front() {
+            if let Slot::Waiting = slot {
+                break;
+            }
+            self.popped += 1;
+            if let Some(Slot::Ready(record, output)) = self.queue.pop_front() {
+                ready.push((record, output));
+            }
+        }
+        ready
+    }
+
+    /// Drop any mates and alignments still waiting, and take the rest of the reads.
+    fn finish(mut self) -> Vec<(bam::record::Record, Output)> {
+        self.expire(INPUT_END);
+        let waiting = self
+            .mates
+            .drain()
+            .map(|(_, waiting)| waiting)
+            .chain(self.followers.drain().flat_map(|(_, followers)| {
+                followers
+                    .into_iter()
+                    .map(|(follower, slot, _)| (follower, slot))
+            }))
+            .collect::<Vec<_>>();
+        for (record, slot) in waiting {
+            self.give_up(record, slot);
+        }
+        self.ready()
     }
-    let mut stats = global_stats.lock().unwrap();
-    stats.reads_out = reads_out;
-    println!("{}", stats);
-    Ok(())
 }
 
+/// A bundle of reads, and the coordinate that every read in the input before it has been
+/// released by, either in this bundle or an earlier one.
+struct Bundle {
+    records: Vec<bam::record::Record>,
+    passthrough: Vec<bam::record::Record>,
+    discarded: Vec<bam::record::Record>,
+    released_before: SortKey,
+}
+
+impl Bundle {
+    /// Take the reads of the bundle along with where they are written.
+    fn reads(self) -> impl Iterator<Item = (bam::record::Record, Output)> {
+        let records = self.records.into_iter().map(|read| (read, Output::Grouped));
+        let passthrough = self
+            .passthrough
+            .into_iter()
+            .map(|read| (read, Output::Passthrough));
+        let discarded = self
+            .discarded
+            .into_iter()
+            .map(|read| (read, Output::Discarded));
+        records.chain(passthrough).chain(discarded)
+    }
+}
+
+/// Splits a coordinate sorted stream of records into bundles that can be grouped independently.
+/// Records are held until the stream has moved far enough past their 5' position that no later
+/// record can share it, then every record up to that point is released as a bundle. Later reads
+/// start at or after the current read, so their 5' positions can only be before it by the length
+/// of their leading soft clip, which must be no more than window.
 struct Bundler<I>
 where
     I: Iterator<Item = Result<rust_htslib::bam::record::Record, Error>>,
 {
     records: I,
+    window: i32,
     last_chr: Option<i32>,
-    next_bundle: Vec<rust_htslib::bam::record::Record>,
+    last_pos: i32,
+    /// Every record on the current reference with a bundle pos before this has been released
+    flushed_before: i32,
+    buffer: BTreeMap<i32, Vec<rust_htslib::bam::record::Record>>,
+}
+
+impl<I> Bundler<I>
+where
+    I: Iterator<Item = Result<rust_htslib::bam::record::Record, Error>>,
+{
+    fn new(records: I, window: i32) -> Self {
+        Bundler {
+            records,
+            window,
+            last_chr: None,
+            last_pos: -1,
+            flushed_before: i32::MIN,
+            buffer: BTreeMap::new(),
+        }
+    }
+
+    /// The position a record is bundled by. Unmapped reads don't have a cigar to find their 5'
+    /// position with, and are only grouped to be counted, so they just use their pos.
+    fn bundle_pos(record: &rust_htslib::bam::record::Record) -> i32 {
+        let cigar = record.cigar();
+        if record.is_unmapped() || cigar.is_empty() {
+            record.pos()
+        } else {
+            Position::five_prime_pos(record, &cigar)
+        }
+    }
+
+    /// Release all buffered records before pos, in order of their bundle pos.
+    fn flush_before(&mut self, pos: i32) -> Bundle {
+        self.flushed_before = pos;
+        let rest = self.buffer.split_off(&pos);
+        let bundle = std::mem::replace(&mut self.buffer, rest);
+        self.make_bundle(bundle)
+    }
+
+    /// Release all buffered records, in order of their bundle pos.
+    fn flush(&mut self) -> Bundle {
+        self.flushed_before = i32::MIN;
+        let bundle = std::mem::replace(&mut self.buffer, BTreeMap::new());
+        self.make_bundle(bundle)
+    }
+
+    fn make_bundle(&self, records: BTreeMap<i32, Vec<rust_htslib::bam::record::Record>>) -> Bundle {
+        // Records within a bundle pos are in input order, so the first is the earliest
+        let released_before = self
+            .buffer
+            .values()
+            .map(|records| sort_key(&records[0]))
+            .min()
+            .unwrap_or(INPUT_END);
+        Bundle {
+            records: records
+                .into_iter()
+                .flat_map(|(_, records)| records)
+                .collect(),
+            passthrough: vec![],
+            discarded: vec![],
+            released_before,
+        }
+    }
 }
 
 impl<I> Iterator for Bundler<I>
 where
     I: Iterator<Item = Result<rust_htslib::bam::record::Record, Error>>,
 {
-    type Item = Vec<rust_htslib::bam::record::Record>;
+    type Item = Result<Bundle, RumiError>;
 
     fn next(&mut self) -> Option<Self::Item> {
-        let mut bundle = vec![];
-        std::mem::swap(&mut self.next_bundle, &mut bundle);
         while let Some(r) = self.records.next() {
-            let record = r.unwrap();
-            if let Some(tid) = self.last_chr {
-                if tid == record.tid() {
-                    bundle.push(record);
-                } else {
-                    self.last_chr = Some(record.tid());
-                    self.next_bundle.push(record);
-                    break;
-                }
-            } else {
+            let record = match r {
+                Ok(record) => record,
+                Err(err) => return Some(Err(err.into())),
+            };
+            let bundle_pos = Bundler::<I>::bundle_pos(&record);
+            if self.last_chr != Some(record.tid()) {
                 self.last_chr = Some(record.tid());
-                bundle.push(record);
+                self.last_pos = record.pos();
+                let bundle = self.flush();
+                self.buffer.entry(bundle_pos).or_default().push(record);
+                if bundle.records.is_empty() {
+                    continue;
+                }
+                return Some(Ok(Bundle {
+                    released_before: sort_key(&self.buffer[&bundle_pos][0]),
+                    ..bundle
+                }));
+            } else if record.pos() < self.last_pos {
+                return Some(Err(RumiError::malformed_record(
+                    &record,
+                    "input bam must be coordinate sorted",
+                )));
+            }
+            self.last_pos = record.pos();
+            // The reads at its 5' position have already gone, so it can't be grouped with them
+            if bundle_pos < self.flushed_before {
+                return Some(Err(RumiError::malformed_record(
+                    &record,
+                    "leading soft clip is longer than bundle_window, which needs to be raised",
+                )));
+            }
+
+            // No record from here on can have a bundle pos before boundary
+            let boundary = record.pos() - self.window;
+            self.buffer.entry(bundle_pos).or_default().push(record);
+            match self.buffer.keys().next() {
+                Some(&first) if first < boundary => return Some(Ok(self.flush_before(boundary))),
+                _ => continue,
             }
         }
-        if bundle.len() > 0 {
-            Some(bundle)
+        let bundle = self.flush();
+        if !bundle.records.is_empty() {
+            Some(Ok(bundle))
         } else {
             None
         }
     }
 }
 
-fn get_tag<'a>(record: &'a bam::record::Record, config: &Config) -> &'a [u8] {
+/// Get the umi of a read, or None if it doesn't have one.
+fn get_tag<'a>(
+    record: &'a bam::record::Record,
+    config: &Config,
+) -> Result<Option<&'a [u8]>, RumiError> {
     if config.umi_in_read_id {
-        match record.qname().split(|&c| c == b'_').last() {
-            Some(tag) => tag,
-            None => panic!("No tag in read id"),
+        let mut parts = record.qname().rsplitn(2, |&c| c == b'_');
+        match (parts.next(), parts.next()) {
+            (Some(tag), Some(_)) if !tag.is_empty() => Ok(Some(tag)),
+            _ => Ok(None),
         }
     } else {
         match record.aux(config.umi_tag.as_bytes()) {
-            Some(tag) => tag.string(),
-            None => panic!("No tag on read"),
+            Some(Aux::String(tag)) if !tag.is_empty() => Ok(Some(tag)),
+            Some(Aux::String(_)) | None => Ok(None),
+            Some(_) => Err(RumiError::malformed_record(
+                record,
+                &format!("{} tag is not a string", config.umi_tag),
+            )),
+        }
+    }
+}
+
+/// The cell barcode of a read, from the cell tag.
+fn cell_barcode<'a>(record: &'a bam::record::Record, config: &Config) -> Option<&'a [u8]> {
+    match record.aux(config.cell_tag.as_bytes()) {
+        Some(Aux::String(cell)) if !cell.is_empty() => Some(cell),
+        _ => None,
+    }
+}
+
+/// A whitelist of cell barcodes to correct the raw cell barcodes of reads to. It's read from a
+/// file with a barcode at the start of each line, which fits the whitelists of both Cell Ranger
+/// and umi_tools whitelist.
+#[derive(Debug)]
+pub struct CellWhitelist {
+    barcodes: Vec<BaseBits>,
+    len: usize,
+    index: UmiIndex,
+}
+
+impl CellWhitelist {
+    pub fn from_path(path: &str) -> Result<Self, RumiError> {
+        let whitelist = std::fs::read_to_string(path)?;
+        let barcodes: Vec<&[u8]> = whitelist
+            .lines()
+            .filter_map(|line| line.split_whitespace().next())
+            .map(str::as_bytes)
+            .collect();
+        CellWhitelist::new(&barcodes)
+    }
+
+    /// Index the barcodes, which must all be the same length, for finding those one mismatch
+    /// from a raw barcode.
+    pub fn new(barcodes: &[&[u8]]) -> Result<Self, RumiError> {
+        let len = match barcodes.first() {
+            Some(barcode) => barcode.len(),
+            None => {
+                return Err(RumiError::InvalidConfig(String::from(
+                    "cell whitelist is empty",
+                )))
+            }
+        };
+        if let Some(barcode) = barcodes
+            .iter()
+            .find(|b| len < 2 || b.len() != len || !is_valid_umi(b))
+        {
+            return Err(RumiError::InvalidConfig(format!(
+                "cell whitelist barcodes must be ACGT, all of the same length of 2 to {} bases: {}",
+                MAX_BASES,
+                String::from_utf8_lossy(barcode)
+            )));
+        }
+        let barcodes: Vec<BaseBits> = barcodes
+            .iter()
+            .map(|barcode| BaseBits::new(barcode).unwrap())
+            .collect();
+        let index = UmiIndex::with_umis(barcodes.iter().enumerate(), len, 1);
+        Ok(CellWhitelist {
+            barcodes,
+            len,
+            index,
+        })
+    }
+
+    /// The whitelisted barcode for a raw barcode. That's the raw barcode if it's whitelisted,
+    /// or else the whitelisted barcode one mismatch from it. When there is more than one, the
+    /// one whose mismatch is at the raw base with the lowest quality wins, since that base is the
+    /// most likely to be a sequencing error. Bases other than ACGT are N's, which are always a
+    /// mismatch, so a raw barcode with one N is corrected like any other. Those that tie can't
+    /// be corrected.
+    pub fn correct(&self, raw: &[u8], qual: Option<&[u8]>) -> Option<Vec<u8>> {
+        if raw.len() != self.len {
+            return None;
+        }
+        // Without qualities every base is as likely to be wrong
+        let qual = qual.filter(|qual| qual.len() == raw.len());
+        let umi = BaseBits::new(raw).unwrap();
+        let mut best: Option<(u8, Vec<u8>)> = None;
+        let mut tied = false;
+        for i in self.index.candidates(&umi).into_iter() {
+            match hamming_dist_none(&umi, &self.barcodes[i]) {
+                0 => return Some(raw.to_vec()),
+                1 => {
+                    let barcode = self.barcodes[i].decode();
+                    let mismatch = barcode.iter().zip(raw).position(|(a, b)| a != b).unwrap();
+                    let base_qual = qual.map_or(0, |qual| qual[mismatch]);
+                    match &best {
+                        Some((best_qual, _)) if *best_qual < base_qual => {}
+                        Some((best_qual, _)) if *best_qual == base_qual => tied = true,
+                        _ => {
+                            best = Some((base_qual, barcode));
+                            tied = false;
+                        }
+                    }
+                }
+                _ => {}
+            }
+        }
+        if tied {
+            None
+        } else {
+            best.map(|(_, barcode)| barcode)
         }
     }
 }
 
+/// The gene a read is assigned to, from the gene tag. Like umi_tools, reads whose gene starts
+/// with __ (htseq-count) or Unassigned (featureCounts) haven't been assigned one, and neither
+/// have those with a gene of - (STARsolo).
+fn gene_id<'a>(record: &'a bam::record::Record, config: &Config) -> Option<&'a [u8]> {
+    match record.aux(config.gene_tag.as_bytes()) {
+        Some(Aux::String(gene))
+            if !gene.is_empty()
+                && gene != b"-"
+                && !gene.starts_with(b"__")
+                && !gene.starts_with(b"Unassigned") =>
+        {
+            Some(gene)
+        }
+        _ => None,
+    }
+}
+
+/// The strand of a duplex molecule that a read came from.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DuplexStrand {
+    A,
+    B,
+}
+
+impl Display for DuplexStrand {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            DuplexStrand::A => write!(f, "A"),
+            DuplexStrand::B => write!(f, "B"),
+        }
+    }
+}
+
+/// Canonicalise a duplex umi like AAAA-CCCC. The two halves swap places between the reads of the
+/// top and bottom strands, so they are put in sorted order to give both strands the same umi.
+/// Reads whose halves were already in order are on strand A, and the rest are on strand B.
+/// Returns None if the umi isn't two halves separated by a '-'.
+fn duplex_umi(umi: &[u8]) -> Option<(Vec<u8>, DuplexStrand)> {
+    let mut halves = umi.split(|&c| c == b'-');
+    match (halves.next(), halves.next(), halves.next()) {
+        (Some(first), Some(second), None) if !first.is_empty() && !second.is_empty() => {
+            if first <= second {
+                Some(([first, second].concat(), DuplexStrand::A))
+            } else {
+                Some(([second, first].concat(), DuplexStrand::B))
+            }
+        }
+        _ => None,
+    }
+}
+
+/// The duplex strand of a read, or None if not in duplex mode or it has no duplex umi.
+pub fn duplex_strand(record: &bam::record::Record, config: &Config) -> Option<DuplexStrand> {
+    if !config.duplex {
+        return None;
+    }
+    match get_tag(record, config) {
+        Ok(Some(tag)) => duplex_umi(tag).map(|(_, strand)| strand),
+        _ => None,
+    }
+}
+
+/// A umi is valid if it is only ACGT and is short enough to encode.
+fn is_valid_umi(umi: &[u8]) -> bool {
+    umi.len() <= MAX_BASES && umi.iter().all(|b| b"ACGT".contains(b))
+}
+
+/// The umi shared by reads that are put in their own group without a umi that can be encoded.
+fn no_umi() -> BaseBits {
+    BaseBits::new(b"").unwrap()
+}
+
+/// Umis that are only in the graph because of UmiPolicy::OwnGroup. These are never connected to
+/// any other umi. Umis with N's are only in their own group if isolate_n, since with
+/// UmiPolicy::Group they are grouped like any other.
+fn is_own_group(umi: &BaseBits, isolate_n: bool) -> bool {
+    let seq = umi.decode();
+    seq.is_empty() || (isolate_n && seq.contains(&b'N'))
+}
+
 pub fn check_record(record: &bam::record::Record, paired_end: bool) -> RecordEvent {
     if paired_end {
         if record.is_unmapped() {
@@ -425,17 +3078,79 @@ pub fn check_record(record: &bam::record::Record, paired_end: bool) -> RecordEve
 }
 
 /// Group reads together based on their positions.
+/// Reads that don't have a usable umi are handled according to the configured UmiPolicy, and
+/// those passed through are returned separately. When paired, only the first read of a pair is
+/// grouped, and the mates are returned with the passed through reads. Secondary and supplementary
+/// alignments are never grouped, and are dropped or returned with the passed through reads
+/// according to their AlignmentPolicy. Dropped reads are returned as well when a discarded bam is
+/// being written. When marking duplicates every read is written, so reads that would be dropped
+/// are passed through instead.
 pub fn group_reads(
     records: Vec<rust_htslib::bam::record::Record>,
     config: &Config,
-) -> (ReadMap, Stats) {
+) -> Result<
+    (
+        ReadMap,
+        Vec<bam::record::Record>,
+        Vec<bam::record::Record>,
+        Stats,
+    ),
+    RumiError,
+> {
     let mut read_map: ReadMap = BTreeMap::new();
+    let mut passthrough = vec![];
+    let mut discarded = vec![];
     let mut stats = Stats::new();
 
-    for record in records.into_iter() {
+    for mut record in records.into_iter() {
         stats.reads_in += 1;
 
-        if config.is_paired && record.is_last_in_template() {
+        // Only primary alignments are grouped, so that other alignments of a read can't be kept
+        // in its place
+        let policy = if record.is_secondary() {
+            stats.secondary += 1;
+            Some((config.secondary, Discard::Secondary))
+        } else if record.is_supplementary() {
+            stats.supplementary += 1;
+            Some((config.supplementary, Discard::Supplementary))
+        } else {
+            None
+        };
+        match policy {
+            None => {}
+            Some((AlignmentPolicy::Drop, reason)) => {
+                drop_read(record, reason, config, &mut passthrough, &mut discarded);
+                continue;
+            }
+            Some(_) => {
+                passthrough.push(record);
+                continue;
+            }
+        }
+
+        // Mates are written along with the read they are paired with, unless it will be dropped.
+        // Both reads of kept chimeric pairs are grouped, since they are too far apart to wait on.
+        let chimeric = record.tid() != record.mtid();
+        if config.is_paired && record.is_last_in_template() && !(chimeric && config.keep_chimeric) {
+            if chimeric {
+                drop_read(
+                    record,
+                    Discard::Chimeric,
+                    config,
+                    &mut passthrough,
+                    &mut discarded,
+                );
+            } else if record.is_mate_unmapped() {
+                drop_read(
+                    record,
+                    Discard::MateUnmapped,
+                    config,
+                    &mut passthrough,
+                    &mut discarded,
+                );
+            } else {
+                passthrough.push(record);
+            }
             continue;
         }
 
@@ -443,10 +3158,24 @@ pub fn group_reads(
             RecordEvent::RecordMapped => {}
             RecordEvent::RecordUnmapped => {
                 stats.reads_unmapped += 1;
+                drop_read(
+                    record,
+                    Discard::Unmapped,
+                    config,
+                    &mut passthrough,
+                    &mut discarded,
+                );
                 continue;
             }
             RecordEvent::RecordUnpaired => {
                 stats.reads_unpaired += 1;
+                drop_read(
+                    record,
+                    Discard::Unpaired,
+                    config,
+                    &mut passthrough,
+                    &mut discarded,
+                );
                 continue;
             }
             RecordEvent::RecordMateUnmapped => {
@@ -454,22 +3183,165 @@ pub fn group_reads(
             }
             RecordEvent::RecordChimeric => {
                 stats.chimeric += 1;
-                continue;
+                if !config.keep_chimeric {
+                    drop_read(
+                        record,
+                        Discard::Chimeric,
+                        config,
+                        &mut passthrough,
+                        &mut discarded,
+                    );
+                    continue;
+                }
+            }
+        }
+
+        if record.cigar().is_empty() {
+            return Err(RumiError::malformed_record(
+                &record,
+                "mapped read has no cigar",
+            ));
+        }
+        // Reads are grouped by their corrected cell barcode, so it replaces any they came with
+        if let Some(whitelist) = &config.whitelist {
+            let raw = match record.aux(config.raw_cell_tag.as_bytes()) {
+                Some(Aux::String(raw)) if !raw.is_empty() => raw.to_vec(),
+                _ => {
+                    stats.missing_cell += 1;
+                    drop_read(
+                        record,
+                        Discard::MissingCell,
+                        config,
+                        &mut passthrough,
+                        &mut discarded,
+                    );
+                    continue;
+                }
+            };
+            let qual = match record.aux(config.cell_qual_tag.as_bytes()) {
+                Some(Aux::String(qual)) => Some(qual.to_vec()),
+                _ => None,
+            };
+            match whitelist.correct(&raw, qual.as_deref()) {
+                Some(cell) => {
+                    if cell != raw {
+                        stats.cell_corrected += 1;
+                    }
+                    set_aux(&mut record, config.cell_tag.as_bytes(), &Aux::String(&cell));
+                }
+                None => {
+                    stats.cell_uncorrectable += 1;
+                    drop_read(
+                        record,
+                        Discard::UncorrectableCell,
+                        config,
+                        &mut passthrough,
+                        &mut discarded,
+                    );
+                    continue;
+                }
             }
         }
+        let position = Position::new(&record, config);
+        if config.per_cell && position.cell.is_none() {
+            stats.missing_cell += 1;
+            drop_read(
+                record,
+                Discard::MissingCell,
+                config,
+                &mut passthrough,
+                &mut discarded,
+            );
+            continue;
+        }
+        if config.per_gene && position.gene.is_none() {
+            stats.missing_gene += 1;
+            drop_read(
+                record,
+                Discard::MissingGene,
+                config,
+                &mut passthrough,
+                &mut discarded,
+            );
+            continue;
+        }
 
-        let tag = get_tag(&record, config);
-        let position = Position::new(&record, config.ignore_splice_pos, config.is_paired);
+        let tag = get_tag(&record, config)?;
+        // Duplex umis that can't be split into halves are invalid
+        let umi = match tag {
+            Some(tag) if config.duplex => Some(duplex_umi(tag).map(|(umi, _)| Cow::Owned(umi))),
+            Some(tag) => Some(Some(Cow::Borrowed(tag))),
+            None => None,
+        };
+        let bb = match umi {
+            Some(Some(ref umi)) if is_valid_umi(umi) => BaseBits::new(umi).unwrap(),
+            Some(umi) => {
+                let tag = tag.unwrap();
+                stats.invalid_umi += 1;
+                match config.invalid_umi {
+                    UmiPolicy::Error => return Err(RumiError::invalid_umi(&record, tag)),
+                    UmiPolicy::Skip => {
+                        drop_read(
+                            record,
+                            Discard::InvalidUmi,
+                            config,
+                            &mut passthrough,
+                            &mut discarded,
+                        );
+                        continue;
+                    }
+                    UmiPolicy::Passthrough => {
+                        passthrough.push(record);
+                        continue;
+                    }
+                    UmiPolicy::OwnGroup => umi
+                        .and_then(|umi| BaseBits::new(&umi).ok())
+                        .unwrap_or_else(no_umi),
+                    UmiPolicy::Group => match umi.and_then(|umi| BaseBits::new(&umi).ok()) {
+                        Some(bb) => bb,
+                        None => return Err(RumiError::invalid_umi(&record, tag)),
+                    },
+                }
+            }
+            None => {
+                stats.missing_umi += 1;
+                match config.missing_umi {
+                    UmiPolicy::Error => return Err(RumiError::missing_umi(&record)),
+                    UmiPolicy::Skip => {
+                        drop_read(
+                            record,
+                            Discard::MissingUmi,
+                            config,
+                            &mut passthrough,
+                            &mut discarded,
+                        );
+                        continue;
+                    }
+                    UmiPolicy::Passthrough => {
+                        passthrough.push(record);
+                        continue;
+                    }
+                    UmiPolicy::OwnGroup | UmiPolicy::Group => no_umi(),
+                }
+            }
+        };
+        let chimeric = position.is_chimeric();
 
         // Add to my reverse lookup
-        let bb = BaseBits::new(tag).unwrap();
         let position_map = read_map.entry(position).or_insert(HashMap::new());
         match position_map.entry(bb) {
             Occupied(entry) => {
                 let rf = entry.into_mut();
                 match &rf.read {
                     ReadCollection::SingleRead(read) => {
-                        if !read_a_ge_b(&read, &record) {
+                        // Chimeric pairs are grouped at both ends, and keeping the lowest qname
+                        // keeps the same pair at each end
+                        let replace = if chimeric {
+                            record.qname() < read.qname()
+                        } else {
+                            !read_a_ge_b(&read, &record)
+                        };
+                        if replace {
                             rf.read = ReadCollection::SingleRead(record);
                         }
                     }
@@ -483,7 +3355,12 @@ pub fn group_reads(
                 rf.freq += 1;
             }
             Vacant(entry) => {
-                if !config.group_only {
+                // Every read is kept when any are written besides the one kept for the group
+                if !config.group_only
+                    && !config.consensus
+                    && !config.mark_duplicates
+                    && config.discarded.is_none()
+                {
                     entry.insert(ReadFreq {
                         read: ReadCollection::SingleRead(record),
                         freq: 1,
@@ -497,35 +3374,158 @@ pub fn group_reads(
             }
         };
     }
-    (read_map, stats)
+    Ok((read_map, passthrough, discarded, stats))
 }
 
-/// Create a graph from the UmiMap
+/// Create a graph from the UmiMap. The nodes are sorted by umi so that ties between umis are
+/// broken the same way from run to run, regardless of the order of the map.
 /// TODO: Inline?
 pub fn build_graph(reads: UmiMap) -> Vec<Node> {
-    reads
+    let mut graph: Vec<Node> = reads
         .into_iter()
         .map(|(umi, freqs)| Node {
             umi,
             connections: vec![],
             freq: freqs,
         })
+        .collect();
+    graph.sort_by(|a, b| {
+        a.umi
+            .code
+            .cmp(&b.umi.code)
+            .then_with(|| a.umi.decode().cmp(&b.umi.decode()))
+    });
+    graph
+}
+
+/// A pigeonhole index over the umis of a graph. If two umis of the same length are within dist
+/// of each other, then splitting both into dist + 1 chunks leaves at least one chunk that is
+/// identical between them. So only umis that share a chunk need to be compared.
+#[derive(Debug)]
+struct UmiIndex {
+    masks: Vec<u64>,
+    chunks: Vec<HashMap<u64, Vec<usize>>>,
+}
+
+impl UmiIndex {
+    /// Index the umis of a graph, leaving out those in their own group. Returns None if the umis
+    /// can't be indexed, either because they are not all the same length or because they are too
+    /// short to split into dist + 1 chunks.
+    fn new(graph: &[Node], dist: u32, isolate_n: bool) -> Option<Self> {
+        let umi_len = graph
+            .iter()
+            .find(|n| !is_own_group(&n.umi, isolate_n))?
+            .umi
+            .decode()
+            .len();
+        let mixed_lengths = graph
+            .iter()
+            .any(|n| !is_own_group(&n.umi, isolate_n) && n.umi.decode().len() != umi_len);
+        if mixed_lengths || dist as usize >= umi_len {
+            return None;
+        }
+        let umis = graph
+            .iter()
+            .enumerate()
+            .filter(|(_, node)| !is_own_group(&node.umi, isolate_n))
+            .map(|(i, node)| (i, &node.umi));
+        Some(UmiIndex::with_umis(umis, umi_len, dist))
+    }
+
+    /// Index umis by their ids. The umis must all be umi_len long, which must be more than dist.
+    fn with_umis<'a, I>(umis: I, umi_len: usize, dist: u32) -> Self
+    where
+        I: Iterator<Item = (usize, &'a BaseBits)>,
+    {
+        // Each base takes up ENCODING_LENGTH bits of the code
+        let num_chunks = dist as usize + 1;
+        let masks: Vec<u64> = (0..num_chunks)
+            .map(|k| {
+                let start = k * umi_len / num_chunks;
+                let end = (k + 1) * umi_len / num_chunks;
+                let width = (end - start) as u32 * ENCODING_LENGTH;
+                ((1u64 << width) - 1) << (start as u32 * ENCODING_LENGTH)
+            })
+            .collect();
+
+        let mut chunks: Vec<HashMap<u64, Vec<usize>>> = vec![HashMap::new(); num_chunks];
+        for (i, umi) in umis {
+            for (k, mask) in masks.iter().enumerate() {
+                chunks[k].entry(umi.code & mask).or_default().push(i);
+            }
+        }
+        UmiIndex { masks, chunks }
+    }
+
+    /// All umis sharing at least one chunk with umi, in ascending order.
+    fn candidates(&self, umi: &BaseBits) -> Vec<usize> {
+        let mut candidates = vec![];
+        for (k, mask) in self.masks.iter().enumerate() {
+            if let Some(matches) = self.chunks[k].get(&(umi.code & mask)) {
+                candidates.extend(matches.iter());
+            }
+        }
+        candidates.sort_unstable();
+        candidates.dedup();
+        candidates
+    }
+}
+
+/// Find all the umis within dist of each umi, in ascending order. At or above index_threshold
+/// umis, a UmiIndex is used instead of an all vs all comparison. Both give the same result. Umis
+/// in their own group have no neighbors.
+fn find_neighbors(
+    graph: &[Node],
+    dist: u32,
+    index_threshold: usize,
+    isolate_n: bool,
+) -> Vec<Vec<usize>> {
+    let index = if graph.len() >= index_threshold {
+        UmiIndex::new(graph, dist, isolate_n)
+    } else {
+        None
+    };
+    let own_group: Vec<bool> = graph
+        .iter()
+        .map(|n| is_own_group(&n.umi, isolate_n))
+        .collect();
+
+    (0..graph.len())
+        .map(|i| {
+            if own_group[i] {
+                return vec![];
+            }
+            let is_neighbor = |&j: &usize| {
+                i != j && !own_group[j] && hamming_dist_none(&graph[i].umi, &graph[j].umi) <= dist
+            };
+            match &index {
+                Some(index) => index
+                    .candidates(&graph[i].umi)
+                    .into_iter()
+                    .filter(is_neighbor)
+                    .collect(),
+                None => (0..graph.len()).filter(is_neighbor).collect(),
+            }
+        })
         .collect()
 }
 
-/// Create the connections between the umis via an all vs all comparison.
+/// Create the connections between the umis that are within dist of each other.
 /// A Connection will only be formed from a larger node to a smaller node.
 /// Larger being defined as node_a >= 2x node_b - 1, the provides the directionality.
+/// Umis with N's are kept apart from the rest if isolate_n.
 /// TODO: Keep a seen list here instead of later? Some connections will be redundant.
-pub fn connect_graph(mut graph: Vec<Node>, dist: u32, counts_factor: u32) -> Vec<Node> {
-    for i in 0..graph.len() {
-        for j in 0..graph.len() {
-            if i == j {
-                continue;
-            }
-            if hamming_dist_none(&graph[i].umi, &graph[j].umi) <= dist
-                && graph[i].freq.freq >= (counts_factor * graph[j].freq.freq) - 1
-            {
+pub fn connect_graph(
+    mut graph: Vec<Node>,
+    dist: u32,
+    counts_factor: u32,
+    index_threshold: usize,
+    isolate_n: bool,
+) -> Vec<Node> {
+    let neighbors = find_neighbors(&graph, dist, index_threshold, isolate_n);
+    for (i, neighbors) in neighbors.into_iter().enumerate() {
+        for j in neighbors {
+            if graph[i].freq.freq >= (counts_factor * graph[j].freq.freq) - 1 {
                 graph[i].connections.push(j);
             }
         }
@@ -533,116 +3533,720 @@ pub fn connect_graph(mut graph: Vec<Node>, dist: u32, counts_factor: u32) -> Vec
     graph
 }
 
-// TODO: Use proper bk tree for faster lookups
-fn determine_umi<'a>(graph: &'a Vec<Node>, allowed_network_depth: usize) -> Vec<Group> {
+/// Create undirected connections between all umis that are within dist of each other,
+/// regardless of their counts. Used by the cluster and adjacency methods.
+pub fn connect_graph_undirected(
+    mut graph: Vec<Node>,
+    dist: u32,
+    index_threshold: usize,
+    isolate_n: bool,
+) -> Vec<Node> {
+    let neighbors = find_neighbors(&graph, dist, index_threshold, isolate_n);
+    for (node, neighbors) in graph.iter_mut().zip(neighbors) {
+        node.connections = neighbors;
+    }
+    graph
+}
+
+/// Connect the graph as required by the configured grouping method.
+fn build_network(reads: UmiMap, config: &Config) -> Vec<Node> {
+    let graph = build_graph(reads);
+    let isolate_n = config.invalid_umi == UmiPolicy::OwnGroup;
+    match config.method {
+        GroupingMethod::Unique | GroupingMethod::Percentile => graph,
+        GroupingMethod::Cluster | GroupingMethod::Adjacency => connect_graph_undirected(
+            graph,
+            config.allowed_read_dist,
+            config.index_threshold,
+            isolate_n,
+        ),
+        GroupingMethod::Directional => connect_graph(
+            graph,
+            config.allowed_read_dist,
+            config.allowed_count_factor,
+            config.index_threshold,
+            isolate_n,
+        ),
+    }
+}
+
+/// Group the nodes of a connected graph with the configured grouping method.
+fn find_groups<'a>(graph: &'a [Node], config: &Config) -> Vec<Group<'a>> {
+    match config.method {
+        GroupingMethod::Unique => determine_unique(graph),
+        GroupingMethod::Percentile => determine_percentile(graph),
+        GroupingMethod::Cluster => determine_cluster(graph),
+        GroupingMethod::Adjacency => determine_adjacency(graph),
+        GroupingMethod::Directional => determine_umi(graph, config.allowed_network_depth),
+    }
+}
+
+/// Create a group from its nodes, choosing the node with the highest count as the master.
+/// Ties go to the earliest node.
+fn new_group(nodes: Vec<&Node>) -> Group<'_> {
+    let master_node = nodes.iter().enumerate().fold(0, |max, (i, x)| {
+        if x.freq.freq > nodes[max].freq.freq {
+            i
+        } else {
+            max
+        }
+    });
+    let umi = &nodes[master_node].umi;
+    Group {
+        nodes,
+        umi,
+        master_node,
+    }
+}
+
+/// Node indicies going from highest counts to lowest.
+fn indicies_by_count(graph: &[Node]) -> Vec<usize> {
+    let mut graph_indicies: Vec<usize> = (0..graph.len()).collect();
+    graph_indicies.sort_by(|&a, &b| graph[b].freq.freq.cmp(&graph[a].freq.freq));
+    graph_indicies
+}
+
+/// Each umi is its own group.
+fn determine_unique(graph: &[Node]) -> Vec<Group<'_>> {
+    indicies_by_count(graph)
+        .into_iter()
+        .map(|x| new_group(vec![&graph[x]]))
+        .collect()
+}
+
+/// Each umi is its own group, but umis with counts at or below 1% of the median count are
+/// dropped. Equivalent of `_get_best_percentile` in umi_tools.
+fn determine_percentile(graph: &[Node]) -> Vec<Group<'_>> {
+    if graph.len() == 1 {
+        return determine_unique(graph);
+    }
+    let counts: Vec<u32> = graph.iter().map(|n| n.freq.freq).collect();
+    let threshold = median(&counts) / 100.0;
+
+    indicies_by_count(graph)
+        .into_iter()
+        .filter(|&x| f64::from(graph[x].freq.freq) > threshold)
+        .map(|x| new_group(vec![&graph[x]]))
+        .collect()
+}
+
+/// Find the connected components of an undirected graph, starting from the highest count nodes.
+/// Each component starts with the node it was found from.
+fn connected_components(graph: &[Node]) -> Vec<Vec<usize>> {
+    let mut components = vec![];
+    let mut seen = vec![false; graph.len()];
+
+    for x in indicies_by_count(graph) {
+        if seen[x] {
+            continue;
+        }
+        seen[x] = true;
+        let mut component = vec![x];
+        let mut i = 0;
+        while i < component.len() {
+            for &y in graph[component[i]].connections.iter() {
+                if !seen[y] {
+                    seen[y] = true;
+                    component.push(y);
+                }
+            }
+            i += 1;
+        }
+        components.push(component);
+    }
+    components
+}
+
+/// Each connected component is a group.
+fn determine_cluster(graph: &[Node]) -> Vec<Group<'_>> {
+    connected_components(graph)
+        .into_iter()
+        .map(|component| new_group(component.into_iter().map(|x| &graph[x]).collect()))
+        .collect()
+}
+
+/// Split each connected component into groups around the fewest most abundant umis that
+/// account for every umi in the component. Equivalent of `_group_adjacency` in umi_tools.
+fn determine_adjacency(graph: &[Node]) -> Vec<Group<'_>> {
+    let mut groups = vec![];
+    let mut observed = vec![false; graph.len()];
+    let mut covered = vec![false; graph.len()];
+
+    for component in connected_components(graph) {
+        // Take umis from highest to lowest count until they and their neighbors cover the
+        // whole component
+        let mut sorted_nodes = component.clone();
+        sorted_nodes.sort_by(|&a, &b| graph[b].freq.freq.cmp(&graph[a].freq.freq));
+        let mut lead_umis = vec![];
+        let mut num_covered = 0;
+        for &x in sorted_nodes.iter() {
+            lead_umis.push(x);
+            for &y in std::iter::once(&x).chain(graph[x].connections.iter()) {
+                if !covered[y] {
+                    covered[y] = true;
+                    num_covered += 1;
+                }
+            }
+            if num_covered == component.len() {
+                break;
+            }
+        }
+
+        for &x in lead_umis.iter() {
+            observed[x] = true;
+        }
+        for &x in lead_umis.iter() {
+            let mut group = vec![&graph[x]];
+            for &y in graph[x].connections.iter() {
+                if !observed[y] {
+                    observed[y] = true;
+                    group.push(&graph[y]);
+                }
+            }
+            groups.push(new_group(group));
+        }
+    }
+    groups
+}
+
+/// A set of node indicies backed by a bitset, for tracking which nodes have been seen while
+/// walking the graph.
+struct NodeSet {
+    bits: Vec<u64>,
+}
+
+impl NodeSet {
+    fn new(size: usize) -> Self {
+        NodeSet {
+            bits: vec![0; (size + 63) / 64],
+        }
+    }
+
+    /// Add a node to the set, returning true if it was not already present.
+    fn insert(&mut self, x: usize) -> bool {
+        let (word, bit) = (x / 64, 1u64 << (x % 64));
+        let is_new = self.bits[word] & bit == 0;
+        self.bits[word] |= bit;
+        is_new
+    }
+}
+
+/// Group the umis via directional adjacency. Starting from the highest count umi that isn't in a
+/// group yet, walk out allowed_network_depth connections and add every node not already in a
+/// group to the new group.
+fn determine_umi(graph: &[Node], allowed_network_depth: usize) -> Vec<Group<'_>> {
     // Group the umis by distance
     let mut groups = vec![];
-    let mut seen: Vec<usize> = Vec::new();
-    // Create a vec of nodes indicies going from highest counts to lowest
-    let mut graph_indicies: Vec<usize> = (0..graph.len()).collect();
-    &graph_indicies.sort_by(|&a, &b| graph[b].freq.freq.cmp(&graph[a].freq.freq));
+    let mut seen = NodeSet::new(graph.len());
+    // The last level each node was added to a frontier at, so that no node is walked through
+    // twice in the same level
+    let mut queued_at: Vec<usize> = vec![0; graph.len()];
+    let mut level = 0;
+    let mut frontier: Vec<usize> = vec![];
+    let mut next_frontier: Vec<usize> = vec![];
 
-    for &x in graph_indicies.iter() {
-        if seen.contains(&x) {
+    for x in indicies_by_count(graph) {
+        if !seen.insert(x) {
             continue;
         }
-        seen.push(x);
         let node = &graph[x];
-        let mut group_holder: Vec<Vec<&Node>> = Vec::new();
 
         // Get all the nodes within 1 hamming dist
         let mut group: Vec<&Node> = vec![];
-        for &x in node.connections.iter() {
-            if !seen.contains(&x) {
-                seen.push(x);
-                group.push(&graph[x]);
+        frontier.clear();
+        for &y in node.connections.iter() {
+            if seen.insert(y) {
+                group.push(&graph[y]);
+                frontier.push(y);
             }
         }
+        // Must add after, otherwise it will be searched again
+        group.push(node);
 
         // Get all the nodes within k hamming dist
         // If two nodes lie equidistant away from a smaller node, it shouldn't matter which node
         // gets the discrepent reads, there would be no real biological way to tell...
-        let mut queue: VecDeque<Vec<usize>> = VecDeque::new();
-        queue.push_back(group.iter().flat_map(|n| n.connections.clone()).collect());
-        for _ in 0..(allowed_network_depth - 1) {
-            if let Some(connections) = queue.pop_front() {
-                let mut new_group: Vec<&Node> = vec![];
-                queue.push_back(
-                    connections
-                        .iter()
-                        .flat_map(|&x| {
-                            if !seen.contains(&x) {
-                                seen.push(x);
-                                new_group.push(&graph[x]);
-                            }
-                            graph[x].connections.clone()
-                        })
-                        .collect(),
-                );
-                group_holder.push(new_group);
+        // Nodes already in a group are still walked through, they just aren't added again.
+        for _ in 1..allowed_network_depth {
+            level += 1;
+            next_frontier.clear();
+            for &y in frontier.iter() {
+                for &z in graph[y].connections.iter() {
+                    if queued_at[z] != level {
+                        queued_at[z] = level;
+                        next_frontier.push(z);
+                    }
+                }
             }
+            for &z in next_frontier.iter() {
+                if seen.insert(z) {
+                    group.push(&graph[z]);
+                }
+            }
+            std::mem::swap(&mut frontier, &mut next_frontier);
         }
-        // Must add after, otherwise it will be searched again
-        group.push(node);
 
-        // Merge all groups and choose concensus umi
-        for g in group_holder {
-            group.extend(g.iter());
+        groups.push(new_group(group));
+    }
+    groups
+}
+
+/// Deduplicate a group of reads that all positioned at the same position
+fn dedup(
+    position: &Position,
+    reads: UmiMap,
+    config: &Config,
+    stats: &mut Stats,
+    umi_stats: &mut Option<UmiStats>,
+    discarded: &mut Vec<bam::record::Record>,
+) -> Vec<bam::record::Record> {
+    let graph = build_network(reads, config);
+    let groups = find_groups(&graph, config);
+    stats.add_groups(&groups);
+    if let Some(umi_stats) = umi_stats {
+        umi_stats.add_position(&graph, &groups);
+    }
+    discard_ungrouped(&graph, &groups, config, discarded);
+    let mut final_reads = vec![];
+
+    for group in groups.iter() {
+        let kept = kept_read(group, position);
+        let mut read = kept.clone();
+        // The kept read is tagged with the group id too, to match up the discarded reads with
+        if config.discarded.is_some() {
+            let group_id = position.group_id(&group.nodes[group.master_node].umi.decode());
+            for other in group.reads().filter(|&other| !std::ptr::eq(other, kept)) {
+                let mut other = other.clone();
+                set_group_id(&mut other, &group_id, config);
+                discard(other, Discard::Duplicate, config, discarded);
+            }
+            set_group_id(&mut read, &group_id, config);
         }
+        tag_family(&mut read, group, config);
+        final_reads.push(read);
+    }
+    final_reads
+}
+
+/// The read dedup keeps for a group, the best read with the group's umi. Chimeric pairs are
+/// grouped at both ends, so the lowest qname is kept instead, to keep the same pair at each end.
+fn kept_read<'a>(group: &Group<'a>, position: &Position) -> &'a bam::record::Record {
+    let reads = group.nodes[group.master_node].freq.read.reads();
+    if position.is_chimeric() {
+        reads.iter().min_by_key(|read| read.qname()).unwrap()
+    } else {
+        best_read(reads.iter())
+    }
+}
+
+/// Discard the reads of the umis at a position that aren't in any group, which the percentile
+/// method drops.
+fn discard_ungrouped(
+    graph: &[Node],
+    groups: &[Group],
+    config: &Config,
+    discarded: &mut Vec<bam::record::Record>,
+) {
+    if config.discarded.is_none() {
+        return;
+    }
+    for read in ungrouped_reads(graph, groups) {
+        discard(read.clone(), Discard::RareUmi, config, discarded);
+    }
+}
+
+/// The reads of the umis at a position that aren't in any group.
+fn ungrouped_reads<'a>(graph: &'a [Node], groups: &[Group]) -> Vec<&'a bam::record::Record> {
+    let grouped: HashSet<&BaseBits> = groups
+        .iter()
+        .flat_map(|group| group.nodes.iter().map(|node| &node.umi))
+        .collect();
+    graph
+        .iter()
+        .filter(|node| !grouped.contains(&node.umi))
+        .flat_map(|node| node.freq.read.reads())
+        .collect()
+}
+
+/// Keep every read at a position, marking all but the read dedup would keep from each group as
+/// duplicates. Every read is tagged with its group id and corrected umi, as in label_groups.
+fn mark_duplicates(
+    position: &Position,
+    reads: UmiMap,
+    config: &Config,
+    stats: &mut Stats,
+    umi_stats: &mut Option<UmiStats>,
+) -> Vec<bam::record::Record> {
+    let graph = build_network(reads, config);
+    let groups = find_groups(&graph, config);
+    stats.add_groups(&groups);
+    if let Some(umi_stats) = umi_stats {
+        umi_stats.add_position(&graph, &groups);
+    }
+    // The reads the percentile method drops aren't duplicates of anything, but are still written
+    let mut records: Vec<bam::record::Record> = ungrouped_reads(&graph, &groups)
+        .into_iter()
+        .map(|read| {
+            let mut read = read.clone();
+            read.unset_duplicate();
+            read
+        })
+        .collect();
 
-        let master_node = group.iter().enumerate().fold(0, |max, (i, x)| {
-            if x.freq.freq > group[max].freq.freq {
-                i
+    for group in groups.iter() {
+        let master_umi = group.nodes[group.master_node].umi.decode();
+        let group_id = position.group_id(&master_umi);
+        let kept = kept_read(group, position);
+        for read in group.reads() {
+            let is_kept = std::ptr::eq(read, kept);
+            let mut read = read.clone();
+            if is_kept {
+                read.unset_duplicate();
+                tag_family(&mut read, group, config);
             } else {
-                max
+                read.set_duplicate();
             }
-        });
-        let umi = &group[master_node].umi;
-        let group = Group {
-            nodes: group,
-            umi,
-            master_node,
-        };
+            set_group_id(&mut read, &group_id, config);
+            let umi = orient_umi(&read, &master_umi, config);
+            set_corrected_umi(&mut read, &umi, config);
+            records.push(read);
+        }
+    }
+    records
+}
 
-        groups.push(group);
+/// Tag a read kept for a group with the number of reads and umis in the group, if configured.
+fn tag_family(read: &mut bam::record::Record, group: &Group, config: &Config) {
+    if let Some(tag) = &config.family_size_tag {
+        let size: u32 = group.nodes.iter().map(|n| n.freq.freq).sum();
+        set_aux(read, tag.as_bytes(), &Aux::Integer(i64::from(size)));
+    }
+    if let Some(tag) = &config.umi_count_tag {
+        let umis = group.nodes.len() as i64;
+        set_aux(read, tag.as_bytes(), &Aux::Integer(umis));
     }
-    groups
 }
 
-/// Deduplicate a group of reads that all positioned at the same position
-fn dedup(reads: UmiMap, config: &Config) -> Vec<bam::record::Record> {
-    let graph = build_graph(reads);
-    let graph = connect_graph(graph, config.allowed_read_dist, config.allowed_count_factor);
-    let groups = determine_umi(&graph, config.allowed_network_depth);
-    let mut final_reads = vec![];
+/// Collapse each group of reads at a position into a single consensus read.
+///
+/// When paired only the first reads of pairs are grouped, so only they are voted on. The
+/// consensus read keeps the mate of the read it was built on, which is written as it is, with the
+/// consensus read's tags, and the mates of the other reads are dropped along with them.
+fn consensus(
+    position: &Position,
+    reads: UmiMap,
+    config: &Config,
+    stats: &mut Stats,
+    umi_stats: &mut Option<UmiStats>,
+    discarded: &mut Vec<bam::record::Record>,
+) -> Vec<bam::record::Record> {
+    let graph = build_network(reads, config);
+    let groups = find_groups(&graph, config);
+    stats.add_groups(&groups);
+    if let Some(umi_stats) = umi_stats {
+        umi_stats.add_position(&graph, &groups);
+    }
+    discard_ungrouped(&graph, &groups, config, discarded);
 
-    for group in groups.into_iter() {
-        let node = group.nodes[group.master_node];
-        if let ReadCollection::SingleRead(read) = &node.freq.read {
-            let read = read.clone();
-            final_reads.push(read);
+    let mut final_reads = vec![];
+    for group in groups.iter() {
+        let reads: Vec<&bam::record::Record> = group
+            .nodes
+            .iter()
+            .flat_map(|node| match &node.freq.read {
+                ReadCollection::ManyReads(reads) => reads.iter(),
+                ReadCollection::SingleRead(_) => unreachable!(),
+            })
+            .collect();
+        let (a_reads, b_reads): (Vec<&bam::record::Record>, Vec<&bam::record::Record>) = reads
+            .iter()
+            .partition(|read| duplex_strand(read, config) != Some(DuplexStrand::B));
+        let mut read = if config.duplex && !a_reads.is_empty() && !b_reads.is_empty() {
+            duplex_consensus_read(&a_reads, &b_reads)
         } else {
-            unreachable!();
+            consensus_read(&reads)
+        };
+        // The other reads the consensus was built from are discarded, tagged with its group id.
+        // The read it was built on and took the name of isn't, so that its mate stays with it.
+        if config.discarded.is_some() {
+            let group_id = position.group_id(&group.nodes[group.master_node].umi.decode());
+            for &other in reads.iter().filter(|other| other.qname() != read.qname()) {
+                let mut other = other.clone();
+                set_group_id(&mut other, &group_id, config);
+                discard(other, Discard::Consensus, config, discarded);
+            }
+            set_aux(
+                &mut read,
+                config.group_tag.as_bytes(),
+                &Aux::String(group_id.as_bytes()),
+            );
         }
+        tag_family(&mut read, group, config);
+        final_reads.push(read);
     }
     final_reads
 }
 
+/// The highest base quality a consensus base can have, the highest that can be written in a sam.
+const MAX_CONSENSUS_QUAL: u32 = 93;
+/// The quality given to consensus bases that the reads mostly disagree on.
+const MIN_CONSENSUS_QUAL: u32 = 2;
+/// The quality that bases without one, which is 0xFF in a bam, vote with. Nothing is known about
+/// how likely they are to be right, so they count for as little as a base can.
+const MISSING_QUAL_VOTE: u32 = MIN_CONSENSUS_QUAL;
+
+/// Tags that describe a read's bases or quals, so are wrong once they're replaced by a consensus.
+const SEQUENCE_TAGS: [&[u8]; 9] = [
+    b"MD", b"NM", b"OQ", b"E2", b"U2", b"BQ", b"CQ", b"CS", b"SM",
+];
+/// Tags written on consensus reads.
+const CONSENSUS_TAGS: [&[u8]; 5] = [b"cd", b"cD", b"cM", b"aD", b"bD"];
+
+/// The alignment of a read that consensus reads are voted on within.
+type Alignment = (i32, CigarString);
+
+fn alignment(read: &bam::record::Record) -> Alignment {
+    (read.pos(), (*read.cigar()).clone())
+}
+
+/// The reads that share the most common alignment (pos and cigar), so that every read has the
+/// same base at each offset. Ties between alignments go to the alignment of the best read, as
+/// picked by read_a_ge_b.
+fn consensus_voters<'a>(reads: &[&'a bam::record::Record]) -> Vec<&'a bam::record::Record> {
+    let best_key = alignment(best_read(reads.iter().cloned()));
+
+    // Count the reads with each alignment, in the order they are first seen
+    let mut alignments: Vec<(Alignment, usize)> = vec![];
+    for read in reads.iter() {
+        let key = alignment(read);
+        match alignments.iter_mut().find(|(k, _)| *k == key) {
+            Some((_, count)) => *count += 1,
+            None => alignments.push((key, 1)),
+        }
+    }
+    let (key, _) = alignments
+        .into_iter()
+        .fold(
+            None,
+            |winner: Option<(Alignment, usize)>, (key, count)| match winner {
+                Some((w_key, w_count))
+                    if w_count > count || (w_count == count && w_key == best_key) =>
+                {
+                    Some((w_key, w_count))
+                }
+                _ => Some((key, count)),
+            },
+        )
+        .unwrap();
+    reads
+        .iter()
+        .cloned()
+        .filter(|read| alignment(read) == key)
+        .collect()
+}
+
+/// Vote on the base at each offset of reads that share an alignment. The base with the highest
+/// sum of qualities wins, and its quality is the sum of the qualities that agree minus those that
+/// disagree. Bases with a missing quality vote with MISSING_QUAL_VOTE. Offsets where the vote is
+/// tied, or where every read has an N, become N.
+/// Returns the consensus seq, quals, and the number of reads with a base at each offset.
+fn vote(voters: &[&bam::record::Record]) -> (Vec<u8>, Vec<u8>, Vec<u32>) {
+    let len = voters[0].seq().len();
+    let seqs: Vec<Vec<u8>> = voters.iter().map(|read| read.seq().as_bytes()).collect();
+    let mut seq = Vec::with_capacity(len);
+    let mut qual = Vec::with_capacity(len);
+    let mut depths = Vec::with_capacity(len);
+    for i in 0..len {
+        let mut scores = [0u32; 4];
+        let mut depth = 0;
+        for (read, read_seq) in voters.iter().zip(seqs.iter()) {
+            let base = match read_seq[i] {
+                b'A' => 0,
+                b'C' => 1,
+                b'G' => 2,
+                b'T' => 3,
+                _ => continue,
+            };
+            scores[base] += match read.qual()[i] {
+                0xFF => MISSING_QUAL_VOTE,
+                q => u32::from(q).min(MAX_CONSENSUS_QUAL),
+            };
+            depth += 1;
+        }
+        depths.push(depth);
+
+        let total: u32 = scores.iter().sum();
+        let top = *scores.iter().max().unwrap();
+        let winners: Vec<usize> = (0..4).filter(|&b| scores[b] == top).collect();
+        if depth == 0 || winners.len() > 1 {
+            seq.push(b'N');
+            qual.push(MIN_CONSENSUS_QUAL as u8);
+        } else {
+            let agree = top;
+            let disagree = total - top;
+            let q = agree.saturating_sub(disagree).min(MAX_CONSENSUS_QUAL);
+            seq.push(b"ACGT"[winners[0]]);
+            qual.push(q.max(MIN_CONSENSUS_QUAL) as u8);
+        }
+    }
+    (seq, qual, depths)
+}
+
+/// Build a single strand consensus read from the reads of a group.
+///
+/// Only the reads chosen by consensus_voters vote, see vote for how each base is called.
+/// The consensus read is built on the best voter, keeping its name, flags, mate information and
+/// tags, except those in SEQUENCE_TAGS which no longer describe the sequence.
+/// The depth at each offset is written as a comma separated list in cd, and the max and min
+/// depths in cD and cM.
+fn consensus_read(reads: &[&bam::record::Record]) -> bam::record::Record {
+    let voters = consensus_voters(reads);
+    let (seq, qual, depths) = vote(&voters);
+    build_consensus(best_read(voters.iter().cloned()), &seq, &qual, &depths)
+}
+
+/// Build a duplex consensus read from the reads of the top (A) and bottom (B) strands of a
+/// molecule. A single strand consensus is called for each strand, and where they agree the base
+/// is kept with the sum of their qualities. Where they disagree the base becomes N. If the strands
+/// don't share an alignment, this falls back to a single strand consensus of all the reads.
+/// The number of reads voting on each strand is written in aD and bD.
+fn duplex_consensus_read(
+    a_reads: &[&bam::record::Record],
+    b_reads: &[&bam::record::Record],
+) -> bam::record::Record {
+    let a_voters = consensus_voters(a_reads);
+    let b_voters = consensus_voters(b_reads);
+    if alignment(a_voters[0]) != alignment(b_voters[0]) {
+        let reads: Vec<&bam::record::Record> =
+            a_reads.iter().chain(b_reads.iter()).cloned().collect();
+        return consensus_read(&reads);
+    }
+
+    let (a_seq, a_qual, a_depths) = vote(&a_voters);
+    let (b_seq, b_qual, b_depths) = vote(&b_voters);
+    let mut seq = Vec::with_capacity(a_seq.len());
+    let mut qual = Vec::with_capacity(a_seq.len());
+    for i in 0..a_seq.len() {
+        if a_seq[i] == b_seq[i] && a_seq[i] != b'N' {
+            let q = (u32::from(a_qual[i]) + u32::from(b_qual[i])).min(MAX_CONSENSUS_QUAL);
+            seq.push(a_seq[i]);
+            qual.push(q as u8);
+        } else {
+            seq.push(b'N');
+            qual.push(MIN_CONSENSUS_QUAL as u8);
+        }
+    }
+    let depths: Vec<u32> = a_depths
+        .iter()
+        .zip(b_depths.iter())
+        .map(|(a, b)| a + b)
+        .collect();
+
+    let template = best_read(a_voters.iter().chain(b_voters.iter()).cloned());
+    let mut read = build_consensus(template, &seq, &qual, &depths);
+    read.push_aux(b"aD", &Aux::Integer(a_voters.len() as i64));
+    read.push_aux(b"bD", &Aux::Integer(b_voters.len() as i64));
+    read
+}
+
+/// Replace the seq and quals of template with a consensus of the same length, drop its
+/// SEQUENCE_TAGS and any consensus tags from an earlier run, and tag it with the consensus depths.
+fn build_consensus(
+    template: &bam::record::Record,
+    seq: &[u8],
+    qual: &[u8],
+    depths: &[u32],
+) -> bam::record::Record {
+    let mut read = template.clone();
+    read.set_data(&replace_seq_qual(template, seq, qual));
+    for tag in SEQUENCE_TAGS.iter().chain(CONSENSUS_TAGS.iter()) {
+        read.remove_aux(tag);
+    }
+    let depth_list: Vec<String> = depths.iter().map(|d| d.to_string()).collect();
+    read.push_aux(b"cd", &Aux::String(depth_list.join(",").as_bytes()));
+    read.push_aux(
+        b"cD",
+        &Aux::Integer(i64::from(*depths.iter().max().unwrap_or(&0))),
+    );
+    read.push_aux(
+        b"cM",
+        &Aux::Integer(i64::from(*depths.iter().min().unwrap_or(&0))),
+    );
+    read
+}
+
+/// The raw data of a read with its seq and quals swapped for ones of the same length.
+///
+/// rust-htslib can only set the seq and quals along with the name and cigar, which drops every tag,
+/// so they're spliced into a copy of the raw data instead.
+fn replace_seq_qual(read: &bam::record::Record, seq: &[u8], qual: &[u8]) -> Vec<u8> {
+    let inner = read.inner();
+    assert_eq!(inner.core.l_qseq as usize, seq.len());
+    assert_eq!(seq.len(), qual.len());
+    let data = unsafe { std::slice::from_raw_parts(inner.data, inner.l_data as usize) };
+    let seq_start = inner.core.l_qname as usize + inner.core.n_cigar as usize * 4;
+    let encode = |base: u8| match base {
+        b'A' => 1,
+        b'C' => 2,
+        b'G' => 4,
+        b'T' => 8,
+        _ => 15,
+    };
+    let mut new = data[..seq_start].to_vec();
+    new.extend(
+        seq.chunks(2)
+            .map(|pair| encode(pair[0]) << 4 | pair.get(1).map_or(0, |&base| encode(base))),
+    );
+    // The packed seq is as long as the old one, so the old quals end where the new ones will
+    let aux_start = new.len() + qual.len();
+    new.extend_from_slice(qual);
+    new.extend_from_slice(&data[aux_start..]);
+    new
+}
+
+/// The best of a set of reads by read_a_ge_b. Earlier reads win ties.
+fn best_read<'a, I>(mut reads: I) -> &'a bam::record::Record
+where
+    I: Iterator<Item = &'a bam::record::Record>,
+{
+    let mut best = reads.next().unwrap();
+    for read in reads {
+        if !read_a_ge_b(best, read) {
+            best = read;
+        }
+    }
+    best
+}
+
 /// TODO: Don't clone the read :(
-fn label_groups(reads: UmiMap, config: &Config) -> Vec<Vec<bam::record::Record>> {
-    let graph = build_graph(reads);
-    let graph = connect_graph(graph, config.allowed_read_dist, config.allowed_count_factor);
-    let groups = determine_umi(&graph, config.allowed_network_depth);
+fn label_groups(
+    position: &Position,
+    reads: UmiMap,
+    config: &Config,
+    stats: &mut Stats,
+    umi_stats: &mut Option<UmiStats>,
+    discarded: &mut Vec<bam::record::Record>,
+) -> Vec<Vec<bam::record::Record>> {
+    let graph = build_network(reads, config);
+    let groups = find_groups(&graph, config);
+    stats.add_groups(&groups);
+    if let Some(umi_stats) = umi_stats {
+        umi_stats.add_position(&graph, &groups);
+    }
+    discard_ungrouped(&graph, &groups, config, discarded);
     let mut records = vec![];
 
     for group in groups.into_iter() {
         let mut group_list = vec![];
-        let master_umi = group.nodes[group.master_node].umi;
+        let master_umi = group.nodes[group.master_node].umi.decode();
+        let group_id = position.group_id(&master_umi);
         for node in group.nodes {
             if let ReadCollection::ManyReads(reads) = &node.freq.read {
-                for read in reads.into_iter() {
+                for read in reads.iter() {
                     let mut read = read.clone();
-                    read.push_aux(b"BX", &bam::record::Aux::String(&master_umi.decode()));
+                    set_group_id(&mut read, &group_id, config);
+                    let umi = orient_umi(&read, &master_umi, config);
+                    set_corrected_umi(&mut read, &umi, config);
                     group_list.push(read);
                 }
             } else {
@@ -655,6 +4259,66 @@ fn label_groups(reads: UmiMap, config: &Config) -> Vec<Vec<bam::record::Record>>
 }
 
 /////////////////////// Helpers
+/// Tag a read with the id of its group. In duplex mode the id ends with the read's strand.
+fn set_group_id(read: &mut bam::record::Record, group_id: &str, config: &Config) {
+    let id = match duplex_strand(read, config) {
+        Some(strand) => format!("{}/{}", group_id, strand),
+        None => String::from(group_id),
+    };
+    set_aux(
+        read,
+        config.group_tag.as_bytes(),
+        &Aux::String(id.as_bytes()),
+    );
+}
+
+/// The corrected umi of a group as it should be written on one of its reads. In duplex mode the
+/// group umi is the read's two halves in sorted order, so they are put back in the read's order
+/// and separated by a '-'.
+fn orient_umi(read: &bam::record::Record, umi: &[u8], config: &Config) -> Vec<u8> {
+    if !config.duplex {
+        return umi.to_vec();
+    }
+    let raw = match get_tag(read, config) {
+        Ok(Some(raw)) => raw,
+        _ => return umi.to_vec(),
+    };
+    let mut halves = raw.split(|&c| c == b'-');
+    match (halves.next(), halves.next(), halves.next()) {
+        (Some(first), Some(second), None) if first.len() + second.len() == umi.len() => {
+            if first <= second {
+                let (a, b) = umi.split_at(first.len());
+                [a, b"-", b].concat()
+            } else {
+                let (b, a) = umi.split_at(second.len());
+                [a, b"-", b].concat()
+            }
+        }
+        _ => umi.to_vec(),
+    }
+}
+
+/// Tag a read with its corrected umi, keeping the umi it came in with in the original umi tag.
+/// Reads without a umi are left untouched.
+fn set_corrected_umi(read: &mut bam::record::Record, umi: &[u8], config: &Config) {
+    let original = match get_tag(read, config) {
+        Ok(Some(original)) => original.to_vec(),
+        _ => return,
+    };
+    set_aux(
+        read,
+        config.original_umi_tag.as_bytes(),
+        &Aux::String(&original),
+    );
+    set_aux(read, config.corrected_umi_tag.as_bytes(), &Aux::String(umi));
+}
+
+/// Set an aux tag on a read, replacing any value it already had.
+fn set_aux(read: &mut bam::record::Record, tag: &[u8], value: &Aux) {
+    read.remove_aux(tag);
+    read.push_aux(tag, value);
+}
+
 /// Decide wich read is better.
 /// For now this uses the simplistic approach of comparing mapq values.
 /// Returns true if alpha is better than beta, false otherwise
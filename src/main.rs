@@ -1,7 +1,7 @@
 #[macro_use]
 extern crate clap;
 //extern crate rumi_dedup_lib;
-use clap::{App, Arg};
+use clap::{App, Arg, ArgMatches, SubCommand};
 use rumi_lib;
 //use basebits::{hamming_dist, BaseBits};
 //use rust_htslib::bam;
@@ -31,86 +31,226 @@ fn main() {
                 .required(true),
         )
         .arg(
-            Arg::with_name("umi_tag")
-                .short("u")
-                .long("umi_tag")
-                .help("The tag holding the umi information.")
-                .default_value("RX")
-                .required(true),
+            Arg::with_name("ignore_splice_pos")
+                .long("ignore_splice_pos")
+                .help(
+                    "If two reads have the same start pos, and contain a splice site, they will be
+                     grouped together, instead of further splitting them based on the splice site",
+                ),
         )
+        .arg(Arg::with_name("per_gene").long("per_gene").help(
+            "Group reads by the gene they are assigned to, from the --gene_tag tag, instead of \
+             by position, like umi_tools --per-gene. Reads without a gene, or with one starting \
+             with __ or Unassigned, or of -, are dropped. Each reference is read in full before \
+             its reads are grouped.",
+        ))
+        .arg(Arg::with_name("group_only").long("group_only").help(
+            "Don't deduplicate reads, just group them given them agroup id, and print them. Rules
+                for filtering out unpaired reads, etc, will still be applied.",
+        ))
+        .arg(Arg::with_name("mark_duplicates").long("mark_duplicates").help(
+            "Write every read instead of removing duplicates, setting the duplicate flag on all \
+             but the kept read of each group, like Picard MarkDuplicates. Every read is tagged \
+             with its group id and corrected umi.",
+        ))
+        .arg(Arg::with_name("keep_chimeric").long("keep_chimeric").help(
+            "With --is_paired, keep pairs whose reads are on different references instead of \
+             dropping them. Both reads are grouped, keyed by their own position and their mate's, \
+             which needs the MC tag.",
+        ))
         .arg(
-            Arg::with_name("allowed_read_dist")
-                .short("d")
-                .long("allowed_read_dist")
-                .help("The distance between umis that will allow them to be counted as adjacent.")
-                .default_value("1")
+            Arg::with_name("pair_key")
+                .long("pair_key")
+                .help(
+                    "How read pairs are keyed with --is_paired. tlen uses the 5' position of read1 \
+                     and the insert size. template uses the unclipped 5' positions and strands of \
+                     both reads, like Picard and fgbio, which needs the MC tag on read1.",
+                )
+                .possible_values(&["tlen", "template"])
+                .default_value("tlen")
                 .takes_value(true),
         )
         .arg(
-            Arg::with_name("allowed_count_factor")
-                .short("c")
-                .long("allowed_count_factor")
+            Arg::with_name("bundle_window")
+                .long("bundle_window")
                 .help(
-                    "The factor to multiply the count of a umi by when determining \
-                     whether or not to group it with other umis within allowed_read_dist. \
-                     include umi_b as adjacent to umi_a if: \
-                     umi_a.counts >= allowed_count_factor * umi_b.counts",
+                    "How far past a position to read before grouping the reads at it. This must \
+                     be at least as long as the longest soft clip at the start of a read, or reads \
+                     with a longer one may stop with an error.",
                 )
-                .default_value("2")
+                .default_value("1000")
                 .takes_value(true),
         )
         .arg(
-            Arg::with_name("allowed_network_depth")
-                .short("n")
-                .long("allowed_network_depth")
+            Arg::with_name("secondary")
+                .long("secondary")
                 .help(
-                    "The number of nodes deep to go when creating a group. If allowed_read_dist \
-                     1, then allowed_network_depth of 2 will enable getting all umis with hamming \
-                     distance of 2 from current umi.",
+                    "What to do with secondary alignments, which are never grouped. follow \
+                     writes them only if their primary is written, with its tags. They don't say \
+                     where their primary is, so they are only found within 1Mb of it on the same \
+                     reference, unless they are the mate of a written read.",
                 )
-                .default_value("2")
+                .possible_values(&["drop", "passthrough", "follow"])
+                .default_value("drop")
                 .takes_value(true),
         )
         .arg(
-            Arg::with_name("umi_in_read_id")
-                .long("umi_in_read_id")
+            Arg::with_name("supplementary")
+                .long("supplementary")
                 .help(
-                "The UMI is located in the read id after the last '_'. Otherwise use the RX tag.",
-            ),
+                    "What to do with supplementary alignments, which are never grouped. follow \
+                     writes them only if their primary, found from the SA tag, is written, with \
+                     its tags.",
+                )
+                .possible_values(&["drop", "passthrough", "follow"])
+                .default_value("drop")
+                .takes_value(true),
         )
         .arg(
-            Arg::with_name("ignore_splice_pos")
-                .long("ignore_splice_pos")
+            Arg::with_name("output_stats")
+                .long("output_stats")
                 .help(
-                    "If two reads have the same start pos, and contain a splice site, they will be
-                     grouped together, instead of further splitting them based on the splice site",
-                ),
+                    "Write umi_tools style tables of umi counts per position, umi edit \
+                     distances, and group sizes to files starting with this prefix.",
+                )
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("family_size_tag")
+                .long("family_size_tag")
+                .help(
+                    "Tag each kept read with the number of reads in its group. Not used with \
+                     --group_only.",
+                )
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("umi_count_tag")
+                .long("umi_count_tag")
+                .help(
+                    "Tag each kept read with the number of distinct umis merged into its group. \
+                     Not used with --group_only.",
+                )
+                .takes_value(true),
         )
-        .arg(Arg::with_name("group_only").long("group_only").help(
-            "Don't deduplicate reads, just group them given them agroup id, and print them. Rules
-                for filtering out unpaired reads, etc, will still be applied.",
-        ))
         .arg(
-            Arg::with_name("is_paired")
-                .long("is_paired")
-                .help("Input is paired end. Read pairs with unmapped read1 will be ignored."),
+            Arg::with_name("group_tag")
+                .long("group_tag")
+                .help(
+                    "The tag to write the group id of each read to with --group_only or \
+                     --mark_duplicates. Ids are made from the group's position and corrected umi, \
+                     so are the same between runs.",
+                )
+                .default_value("MI")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("corrected_umi_tag")
+                .long("corrected_umi_tag")
+                .help(
+                    "The tag to write the corrected umi of each read to with --group_only or \
+                     --mark_duplicates.",
+                )
+                .default_value("RX")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("original_umi_tag")
+                .long("original_umi_tag")
+                .help(
+                    "The tag to keep the umi each read came in with in, since the corrected umi \
+                     may replace it, with --group_only or --mark_duplicates.",
+                )
+                .default_value("OX")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("discarded")
+                .long("discarded")
+                .help(
+                    "Write the reads that aren't kept to this bam, tagged with why, and with the \
+                     group id of the group they were collapsed into. The kept reads are tagged \
+                     with their group id too. Can't be used with --mark_duplicates, which keeps \
+                     every read.",
+                )
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("discard_tag")
+                .long("discard_tag")
+                .help("The tag to write why a read was discarded to with --discarded.")
+                .default_value("XD")
+                .takes_value(true),
+        )
+        .arg(Arg::with_name("consensus").long("consensus").help(
+            "Instead of keeping the best read from each group, build a consensus read from \
+             all the reads in the group by quality weighted voting at each base. With \
+             --is_paired only the first reads are voted on, and the consensus is written with \
+             the unchanged mate of the read it was built on.",
+        ))
+        .arg(Arg::with_name("duplex").long("duplex").help(
+            "Umis are duplex umis like AAAA-CCCC, whose halves swap between the top and bottom \
+             strands. The halves are put in order so both strands group together, and with \
+             --group_only reads are given an MI tag of group/A or group/B for their strand. \
+             With --consensus, groups with both strands get a duplex consensus read. Reads of \
+             the two strands only start at the same position when mates are grouped on their \
+             own, so it can't be used with --is_paired.",
+        ))
+        .arg(Arg::with_name("unordered").long("unordered").help(
+            "Write reads as soon as their bundle is processed instead of in coordinate \
+             order. The output will need to be sorted.",
+        ))
+        .args(&grouping_args())
+        .subcommand(
+            SubCommand::with_name("count")
+                .about(
+                    "Count the molecules of each gene, and each cell with --per_cell, instead \
+                     of writing a bam",
+                )
+                .arg(
+                    Arg::with_name("INBAM")
+                        .help("Input bam file. Use - if stdin")
+                        .default_value("-")
+                        .required(true)
+                        .index(1),
+                )
+                .arg(
+                    Arg::with_name("OUTPUT")
+                        .short("o")
+                        .long("output")
+                        .help(
+                            "Where to write the counts. Use - if stdout. With --format mtx, the \
+                             directory to write matrix.mtx, barcodes.tsv and features.tsv to.",
+                        )
+                        .default_value("-")
+                        .required(true),
+                )
+                .arg(
+                    Arg::with_name("count_format")
+                        .long("format")
+                        .help(
+                            "The format to write the counts in. tsv has a gene, cell and count \
+                             per line, like umi_tools count. mtx is a Matrix Market matrix of \
+                             genes by cells, laid out like Cell Ranger's, and needs --per_cell.",
+                        )
+                        .possible_values(&["tsv", "mtx"])
+                        .default_value("tsv")
+                        .takes_value(true),
+                )
+                .args(&grouping_args()),
         )
         .get_matches();
 
-    // Parse Args
-    let config = rumi_lib::Config {
-        input_bam: value_t!(matches, "INBAM", String).unwrap(),
-        output_bam: value_t!(matches, "OUTBAM", String).unwrap(),
-        umi_tag: value_t!(matches, "umi_tag", String).unwrap(),
-        allowed_read_dist: value_t!(matches, "allowed_read_dist", u32).unwrap(),
-        allowed_count_factor: value_t!(matches, "allowed_count_factor", u32).unwrap(),
-        allowed_network_depth: value_t!(matches, "allowed_network_depth", usize).unwrap(),
-        umi_in_read_id: matches.is_present("umi_in_read_id"),
-        ignore_splice_pos: matches.is_present("ignore_splice_pos"),
-        group_only: matches.is_present("group_only"),
-        is_paired: matches.is_present("is_paired"),
-    };
+    if let Some(matches) = matches.subcommand_matches("count") {
+        let config = parse_config(matches);
+        if let Err(e) = rumi_lib::run_count(&config) {
+            eprintln!("An error occured: {}", e);
+            process::exit(1);
+        }
+        return;
+    }
 
+    let config = parse_config(&matches);
     if !config.group_only {
         if let Err(e) = rumi_lib::run_dedup(&config) {
             eprintln!("An error occured: {}", e);
@@ -121,3 +261,144 @@ fn main() {
         process::exit(1);
     }
 }
+
+/// The arguments for how reads are grouped, shared by deduplicating and counting.
+fn grouping_args<'a, 'b>() -> Vec<Arg<'a, 'b>> {
+    vec![
+        Arg::with_name("umi_tag")
+            .short("u")
+            .long("umi_tag")
+            .help("The tag holding the umi information.")
+            .default_value("RX")
+            .required(true),
+        Arg::with_name("allowed_read_dist")
+            .short("d")
+            .long("allowed_read_dist")
+            .help("The distance between umis that will allow them to be counted as adjacent.")
+            .default_value("1")
+            .takes_value(true),
+        Arg::with_name("allowed_count_factor")
+            .short("c")
+            .long("allowed_count_factor")
+            .help(
+                "The factor to multiply the count of a umi by when determining \
+                     whether or not to group it with other umis within allowed_read_dist. \
+                     include umi_b as adjacent to umi_a if: \
+                     umi_a.counts >= allowed_count_factor * umi_b.counts",
+            )
+            .default_value("2")
+            .takes_value(true),
+        Arg::with_name("allowed_network_depth")
+            .short("n")
+            .long("allowed_network_depth")
+            .help(
+                "The number of nodes deep to go when creating a group. If allowed_read_dist \
+                     1, then allowed_network_depth of 2 will enable getting all umis with hamming \
+                     distance of 2 from current umi.",
+            )
+            .default_value("2")
+            .takes_value(true),
+        Arg::with_name("umi_in_read_id")
+            .long("umi_in_read_id")
+            .help(
+                "The UMI is located in the read id after the last '_'. Otherwise use the RX tag.",
+            ),
+        Arg::with_name("per_cell").long("per_cell").help(
+            "Group reads within each cell barcode, from the --cell_tag tag, like umi_tools \
+             --per-cell. Reads without a cell barcode are dropped.",
+        ),
+        Arg::with_name("cell_tag")
+            .long("cell_tag")
+            .help("The tag holding the cell barcode with --per_cell.")
+            .default_value("CB")
+            .takes_value(true),
+        Arg::with_name("cell_whitelist")
+            .long("cell_whitelist")
+            .help(
+                "With --per_cell, correct the raw cell barcode of each read to this whitelist, \
+                 with a barcode at the start of each line, and write it to the --cell_tag tag. \
+                 Barcodes one mismatch from a whitelisted barcode are corrected to it, or to the \
+                 one whose mismatch is at the lowest quality base if there are more. Reads whose \
+                 barcode can't be corrected are dropped.",
+            )
+            .takes_value(true),
+        Arg::with_name("raw_cell_tag")
+            .long("raw_cell_tag")
+            .help("The tag holding the raw cell barcode with --cell_whitelist.")
+            .default_value("CR")
+            .takes_value(true),
+        Arg::with_name("cell_qual_tag")
+            .long("cell_qual_tag")
+            .help("The tag holding the qualities of the raw cell barcode with --cell_whitelist.")
+            .default_value("CY")
+            .takes_value(true),
+        Arg::with_name("gene_tag")
+            .long("gene_tag")
+            .help("The tag holding the gene id with --per_gene, and when counting.")
+            .default_value("XT")
+            .takes_value(true),
+        Arg::with_name("is_paired")
+            .long("is_paired")
+            .help("Input is paired end. Read pairs with unmapped read1 will be ignored."),
+        Arg::with_name("method")
+            .long("method")
+            .help(
+                "The method used to group umis at a position. These mirror the methods \
+                     offered by umi_tools.",
+            )
+            .possible_values(&[
+                "unique",
+                "percentile",
+                "cluster",
+                "adjacency",
+                "directional",
+            ])
+            .default_value("directional")
+            .takes_value(true),
+        Arg::with_name("index_threshold")
+            .long("index_threshold")
+            .help(
+                "The number of umis at a position at which to index the umis for finding \
+                     neighbors, instead of comparing every umi against every other umi.",
+            )
+            .default_value("100")
+            .takes_value(true),
+        Arg::with_name("missing_umi")
+            .long("missing_umi")
+            .help(
+                "What to do with reads that have no umi. skip drops them, passthrough writes \
+                     them out untouched, and own_group groups them apart from reads with umis.",
+            )
+            .possible_values(&["error", "skip", "passthrough", "own_group"])
+            .default_value("error")
+            .takes_value(true),
+        Arg::with_name("invalid_umi")
+            .long("invalid_umi")
+            .help(
+                "What to do with reads whose umi has bases other than ACGT, or is longer \
+                     than 21 bases. group groups them like any other, with the other bases as N's \
+                     that never match, and stops on umis too long to encode. own_group keeps them \
+                     from being grouped with any other umi.",
+            )
+            .possible_values(&["error", "skip", "passthrough", "own_group", "group"])
+            .default_value("group")
+            .takes_value(true),
+        Arg::with_name("stats")
+            .long("stats")
+            .help("Write the run stats and settings to this file.")
+            .takes_value(true),
+        Arg::with_name("stats_format")
+            .long("stats_format")
+            .help("The format to write the stats file in.")
+            .possible_values(&["json", "tsv"])
+            .default_value("json")
+            .takes_value(true),
+    ]
+}
+
+fn parse_config(matches: &ArgMatches) -> rumi_lib::Config {
+    rumi_lib::Config::from_args(matches).unwrap_or_else(|e| {
+        eprintln!("An error occured: {}", e);
+        process::exit(1);
+    })
+}
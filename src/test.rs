@@ -1,5 +1,6 @@
 use super::*;
 use rust_htslib::bam;
+use std::collections::HashSet;
 
 macro_rules! btreemap {
     ( $b:expr; $($x:expr => $y:expr),* ) => ({
@@ -115,7 +116,7 @@ fn test_group_reads_small() {
         .map(|&r| bam::record::Record::from_sam(&header, r).unwrap())
         .collect();
     let expected_group: ReadMap = btreemap![
-            Position {pos: 61240265, is_spliced: None, is_rev: false, target: 10, tlen: None} => map![
+            Position {pos: 61240265, is_spliced: None, is_rev: false, target: 10, tlen: None, mate: None, cell: None, gene: None} => map![
             BaseBits::new(b"CAGTA").unwrap() => ReadFreq {
                 read: ReadCollection::SingleRead(bam::record::Record::from_sam(&header, records_raw[1]).unwrap()),
                 freq: 6,
@@ -127,20 +128,9 @@ fn test_group_reads_small() {
         ]
     ];
 
-    let config = Config {
-        input_bam: String::from("INPUT"),
-        output_bam: String::from("OUTPUT"),
-        umi_tag: String::from("RX"),
-        allowed_read_dist: 1,
-        allowed_count_factor: 2,
-        allowed_network_depth: 2,
-        umi_in_read_id: false,
-        group_only: false,
-        ignore_splice_pos: false,
-        is_paired: false,
-    };
+    let config = Config::builder().build().unwrap();
 
-    let (grouped, _) = group_reads(records, &config);
+    let (grouped, _, _, _) = group_reads(records, &config).unwrap();
 
     // Check the read_groups
     check_readgroups(grouped, expected_group);
@@ -171,7 +161,7 @@ fn test_group_reads_complex() {
         .map(|&r| bam::record::Record::from_sam(&header, r).unwrap())
         .collect();
     let expected_group: ReadMap = btreemap![
-            Position {pos: 61240265, is_spliced: None, is_rev: false, target: 10, tlen: None} => map![
+            Position {pos: 61240265, is_spliced: None, is_rev: false, target: 10, tlen: None, mate: None, cell: None, gene: None} => map![
                 BaseBits::new(b"CAGTA").unwrap() => ReadFreq {
                     read: ReadCollection::SingleRead(bam::record::Record::from_sam(&header, records_raw[2]).unwrap()),
                     freq: 4,
@@ -181,19 +171,19 @@ fn test_group_reads_complex() {
                     freq: 1,
                 }
         ],
-            Position {pos: 61240291, is_spliced: None, is_rev: true, target: 10, tlen: None} => map![
+            Position {pos: 61240291, is_spliced: None, is_rev: true, target: 10, tlen: None, mate: None, cell: None, gene: None} => map![
                 BaseBits::new(b"CAGTA").unwrap() => ReadFreq {
                     read: ReadCollection::SingleRead(bam::record::Record::from_sam(&header, records_raw[1]).unwrap()),
                     freq: 1,
                 }
         ],
-            Position {pos: 61240264, is_spliced: None, is_rev: false, target: 10, tlen: None} => map![
+            Position {pos: 61240264, is_spliced: None, is_rev: false, target: 10, tlen: None, mate: None, cell: None, gene: None} => map![
                 BaseBits::new(b"CAGTA").unwrap() => ReadFreq {
                     read: ReadCollection::SingleRead(bam::record::Record::from_sam(&header, records_raw[7]).unwrap()),
                     freq: 1,
                 }
         ],
-            Position {pos: 61240265, is_spliced: None, is_rev: false, target: 9,tlen: None} => map![
+            Position {pos: 61240265, is_spliced: None, is_rev: false, target: 9,tlen: None, mate: None, cell: None, gene: None} => map![
                 BaseBits::new(b"CAGTA").unwrap() => ReadFreq {
                     read: ReadCollection::SingleRead(bam::record::Record::from_sam(&header, records_raw[6]).unwrap()),
                     freq: 1,
@@ -201,20 +191,9 @@ fn test_group_reads_complex() {
         ]
     ];
 
-    let config = Config {
-        input_bam: String::from("INPUT"),
-        output_bam: String::from("OUTPUT"),
-        umi_tag: String::from("RX"),
-        allowed_read_dist: 1,
-        allowed_count_factor: 2,
-        allowed_network_depth: 2,
-        umi_in_read_id: false,
-        group_only: false,
-        ignore_splice_pos: false,
-        is_paired: false,
-    };
+    let config = Config::builder().build().unwrap();
 
-    let (grouped, _) = group_reads(records, &config);
+    let (grouped, _, _, _) = group_reads(records, &config).unwrap();
 
     // Check the read_groups
     check_readgroups(grouped, expected_group);
@@ -233,7 +212,7 @@ fn test_read_groups_umi_tools() {
         .map(|&r| bam::record::Record::from_sam(&header, r).unwrap())
         .collect();
     let expected_group: ReadMap = btreemap![
-            Position {pos: 4078334, is_spliced: None, is_rev: true, target: 10, tlen: None} => map![
+            Position {pos: 4078334, is_spliced: None, is_rev: true, target: 10, tlen: None, mate: None, cell: None, gene: None} => map![
                 BaseBits::new(b"ATAAA").unwrap() => ReadFreq {
                     read: ReadCollection::SingleRead(bam::record::Record::from_sam(&header, records_raw[1]).unwrap()),
                     freq: 3,
@@ -241,20 +220,9 @@ fn test_read_groups_umi_tools() {
         ]
     ];
 
-    let config = Config {
-        input_bam: String::from("INPUT"),
-        output_bam: String::from("OUTPUT"),
-        umi_tag: String::from("RX"),
-        allowed_read_dist: 1,
-        allowed_count_factor: 2,
-        allowed_network_depth: 2,
-        umi_in_read_id: false,
-        group_only: false,
-        ignore_splice_pos: false,
-        is_paired: false,
-    };
+    let config = Config::builder().build().unwrap();
 
-    let (grouped, _) = group_reads(records, &config);
+    let (grouped, _, _, _) = group_reads(records, &config).unwrap();
 
     // Check the read_groups
     check_readgroups(grouped, expected_group);
@@ -276,52 +244,41 @@ fn test_read_groups_cigars() {
         .map(|&r| bam::record::Record::from_sam(&header, r).unwrap())
         .collect();
     let expected_group: ReadMap = btreemap![
-            Position {pos: 4078330, is_spliced: None, is_rev: true, target: 10, tlen: None} => map![
+            Position {pos: 4078330, is_spliced: None, is_rev: true, target: 10, tlen: None, mate: None, cell: None, gene: None} => map![
                 BaseBits::new(b"ATAAA").unwrap() => ReadFreq {
                     read: ReadCollection::SingleRead(bam::record::Record::from_sam(&header, records_raw[1]).unwrap()),
                     freq: 1,
                 }],
-            Position {pos: 4078331, is_spliced: Some(35), is_rev: true, target: 10,tlen: None} => map![
+            Position {pos: 4078331, is_spliced: Some(35), is_rev: true, target: 10,tlen: None, mate: None, cell: None, gene: None} => map![
                 BaseBits::new(b"ATAAA").unwrap() => ReadFreq {
                     read: ReadCollection::SingleRead(bam::record::Record::from_sam(&header, records_raw[0]).unwrap()),
                     freq: 1,
                 }],
-            Position {pos: 4078334, is_spliced: Some(15), is_rev: true, target: 10,tlen: None} => map![
+            Position {pos: 4078334, is_spliced: Some(15), is_rev: true, target: 10,tlen: None, mate: None, cell: None, gene: None} => map![
                 BaseBits::new(b"ATAAA").unwrap() => ReadFreq {
                     read: ReadCollection::SingleRead(bam::record::Record::from_sam(&header, records_raw[2]).unwrap()),
                     freq: 1,
                 }],
-            Position {pos: 4078296, is_spliced: None, is_rev: false, target: 10,tlen: None} => map![
+            Position {pos: 4078296, is_spliced: None, is_rev: false, target: 10,tlen: None, mate: None, cell: None, gene: None} => map![
                 BaseBits::new(b"ATAAA").unwrap() => ReadFreq {
                     read: ReadCollection::SingleRead(bam::record::Record::from_sam(&header, records_raw[3]).unwrap()),
                     freq: 1,
                 }],
-            Position {pos: 4078297, is_spliced: Some(33), is_rev: false, target: 10,tlen: None} => map![
+            Position {pos: 4078297, is_spliced: Some(33), is_rev: false, target: 10,tlen: None, mate: None, cell: None, gene: None} => map![
                 BaseBits::new(b"ATAAA").unwrap() => ReadFreq {
                     read: ReadCollection::SingleRead(bam::record::Record::from_sam(&header, records_raw[4]).unwrap()),
                     freq: 1,
                 }],
-            Position {pos: 4078297, is_spliced: Some(15), is_rev: false, target: 10,tlen: None} => map![
+            Position {pos: 4078297, is_spliced: Some(15), is_rev: false, target: 10,tlen: None, mate: None, cell: None, gene: None} => map![
                 BaseBits::new(b"ATAAA").unwrap() => ReadFreq {
                     read: ReadCollection::SingleRead(bam::record::Record::from_sam(&header, records_raw[5]).unwrap()),
                     freq: 1,
                 }]
     ];
 
-    let config = Config {
-        input_bam: String::from("INPUT"),
-        output_bam: String::from("OUTPUT"),
-        umi_tag: String::from("RX"),
-        allowed_read_dist: 1,
-        allowed_count_factor: 2,
-        allowed_network_depth: 2,
-        umi_in_read_id: false,
-        group_only: false,
-        ignore_splice_pos: false,
-        is_paired: false,
-    };
+    let config = Config::builder().build().unwrap();
 
-    let (grouped, _) = group_reads(records, &config);
+    let (grouped, _, _, _) = group_reads(records, &config).unwrap();
 
     // Check the read_groups
     check_readgroups(grouped, expected_group);
@@ -372,23 +329,14 @@ fn test_graph_small() {
         },
     ];
 
-    let config = Config {
-        input_bam: String::from("INPUT"),
-        output_bam: String::from("OUTPUT"),
-        umi_tag: String::from("RX"),
-        allowed_read_dist: 1,
-        allowed_count_factor: 2,
-        allowed_network_depth: 2,
-        umi_in_read_id: false,
-        group_only: false,
-        ignore_splice_pos: false,
-        is_paired: false,
-    };
+    let config = Config::builder().build().unwrap();
 
     let graph = connect_graph(
         uncon_graph,
         config.allowed_read_dist,
         config.allowed_count_factor,
+        config.index_threshold,
+        false,
     );
     println!("{:#?}", graph);
     check_graph(graph, expected);
@@ -512,23 +460,14 @@ fn test_graph_umi() {
         },
     ];
 
-    let config = Config {
-        input_bam: String::from("INPUT"),
-        output_bam: String::from("OUTPUT"),
-        umi_tag: String::from("RX"),
-        allowed_read_dist: 1,
-        allowed_count_factor: 2,
-        allowed_network_depth: 2,
-        umi_in_read_id: false,
-        group_only: false,
-        ignore_splice_pos: false,
-        is_paired: false,
-    };
+    let config = Config::builder().build().unwrap();
 
     let graph = connect_graph(
         uncon_graph,
         config.allowed_read_dist,
         config.allowed_count_factor,
+        config.index_threshold,
+        false,
     );
     println!("{:#?}", graph);
     check_graph(graph, expected);
@@ -631,18 +570,7 @@ fn test_determine_umi() {
     };
     let expected = vec![&node1, &node2];
 
-    let config = Config {
-        input_bam: String::from("INPUT"),
-        output_bam: String::from("OUTPUT"),
-        umi_tag: String::from("RX"),
-        allowed_read_dist: 1,
-        allowed_count_factor: 2,
-        allowed_network_depth: 2,
-        umi_in_read_id: false,
-        group_only: false,
-        ignore_splice_pos: false,
-        is_paired: false,
-    };
+    let config = Config::builder().build().unwrap();
 
     let grouping = determine_umi(&graph, config.allowed_network_depth);
     // Test that nodes can't be double added to two different groups. Otherwise the group_only ends
@@ -652,3 +580,1725 @@ fn test_determine_umi() {
     let grouping: Vec<&Node> = grouping.iter().map(|n| n.nodes[n.master_node]).collect();
     check_umi(grouping, expected);
 }
+
+/// Build an unconnected graph of single reads from umis and their counts.
+fn make_graph(umis: &[(&[u8], u32)]) -> Vec<Node> {
+    let header = get_header();
+    umis.iter()
+        .map(|&(umi, freq)| Node {
+            umi: BaseBits::new(umi).unwrap(),
+            freq: ReadFreq {
+                read: ReadCollection::SingleRead(bam::record::Record::from_sam(&header,
+                    b"SRR2057595.297818_ATTG	0	chr19	61240266	255	26M	*	0	0	*	*	XA:i:1	MD:Z:12C13	NM:i:1	RX:Z:ATTG").unwrap()),
+                freq,
+            },
+            connections: vec![],
+        })
+        .collect()
+}
+
+fn group_umis(grouping: &[Group]) -> Vec<Vec<Vec<u8>>> {
+    grouping
+        .iter()
+        .map(|g| g.nodes.iter().map(|n| n.umi.decode()).collect())
+        .collect()
+}
+
+#[test]
+fn test_grouping_methods() {
+    // Test the umis found in the umi blog post:
+    // https://cgatoxford.files.wordpress.com/2015/08/schematic_25-e1443714121688.png
+    let umis: Vec<(&[u8], u32)> = vec![
+        (b"ATTG", 1),
+        (b"ATTA", 456),
+        (b"ATTT", 2),
+        (b"AGTA", 72),
+        (b"AGTC", 1),
+        (b"AGGA", 90),
+    ];
+
+    let graph = make_graph(&umis);
+    let grouping = determine_unique(&graph);
+    assert_eq!(grouping.len(), 6);
+    assert_eq!(grouping[0].umi.decode(), b"ATTA".to_vec());
+
+    let grouping = determine_percentile(&graph);
+    assert_eq!(grouping.len(), 6);
+
+    let graph = connect_graph_undirected(make_graph(&umis), 1, 100, false);
+    let grouping = determine_cluster(&graph);
+    assert_eq!(grouping.len(), 1);
+    assert_eq!(grouping[0].nodes.len(), 6);
+    assert_eq!(grouping[0].umi.decode(), b"ATTA".to_vec());
+
+    let grouping = determine_adjacency(&graph);
+    assert_eq!(
+        group_umis(&grouping),
+        vec![
+            vec![b"ATTA".to_vec(), b"ATTG".to_vec(), b"ATTT".to_vec()],
+            vec![b"AGGA".to_vec()],
+            vec![b"AGTA".to_vec(), b"AGTC".to_vec()],
+        ]
+    );
+
+    let graph = connect_graph(make_graph(&umis), 1, 2, 100, false);
+    let grouping = determine_umi(&graph, 2);
+    assert_eq!(grouping.len(), 2);
+}
+
+#[test]
+fn test_percentile_drops_rare_umis() {
+    let graph = make_graph(&[(b"ATTG", 1), (b"ATTA", 456), (b"CCCC", 300), (b"GGGG", 200)]);
+    let grouping = determine_percentile(&graph);
+    // median is 250, so anything at or below 2.5 is dropped
+    assert_eq!(
+        group_umis(&grouping),
+        vec![
+            vec![b"ATTA".to_vec()],
+            vec![b"CCCC".to_vec()],
+            vec![b"GGGG".to_vec()]
+        ]
+    );
+}
+
+#[test]
+fn test_indexed_graph_matches_all_vs_all() {
+    // Generate a few hundred umis with a simple lcg so the test is deterministic
+    let mut state: u64 = 42;
+    let mut next = || {
+        state = state
+            .wrapping_mul(6364136223846793005)
+            .wrapping_add(1442695040888963407);
+        state >> 33
+    };
+    let mut seen = HashSet::new();
+    let mut umis: Vec<(Vec<u8>, u32)> = vec![];
+    while umis.len() < 500 {
+        let umi: Vec<u8> = (0..8).map(|_| b"ACGT"[(next() % 4) as usize]).collect();
+        if seen.insert(umi.clone()) {
+            umis.push((umi, (next() % 50 + 1) as u32));
+        }
+    }
+    // Throw in some umis with Ns, which always count as mismatches
+    umis.push((b"ACGTNCGT".to_vec(), 3));
+    umis.push((b"ACGTNCGA".to_vec(), 1));
+    let umis: Vec<(&[u8], u32)> = umis.iter().map(|(u, c)| (u.as_slice(), *c)).collect();
+
+    for &dist in [1, 2, 3].iter() {
+        let expected = connect_graph(make_graph(&umis), dist, 2, usize::MAX, false);
+        let indexed = connect_graph(make_graph(&umis), dist, 2, 0, false);
+        check_graph(indexed, expected);
+
+        let expected = connect_graph_undirected(make_graph(&umis), dist, usize::MAX, false);
+        let indexed = connect_graph_undirected(make_graph(&umis), dist, 0, false);
+        check_graph(indexed, expected);
+    }
+    assert!(UmiIndex::new(&make_graph(&umis), 2, false).is_some());
+
+    // Mixed lengths fall back to comparing everything
+    let graph = make_graph(&[(b"ACGT", 4), (b"ACGTA", 1)]);
+    assert!(UmiIndex::new(&graph, 1, false).is_none());
+}
+
+#[test]
+fn test_determine_umi_many_umis() {
+    // Chains of 4 umis, each connected to the next, with counts halving along the chain. With a
+    // network depth of 2 each chain is split into a group of 3 and a group of 1.
+    let mut umis: Vec<(Vec<u8>, u32)> = vec![];
+    for i in 0..12_500u32 {
+        let mut umi = b"AAAAAAAAAAAA".to_vec();
+        for (k, &base) in [0, 1, 2, 3, 6, 7, 8].iter().enumerate() {
+            umi[base] = b"ACGT"[((i >> (2 * k)) & 3) as usize];
+        }
+        for (&count, &step) in [800, 400, 200, 100].iter().zip([4, 9, 5, 10].iter()) {
+            umis.push((umi.clone(), count));
+            umi[step] = b'C';
+        }
+    }
+    let umis: Vec<(&[u8], u32)> = umis.iter().map(|(u, c)| (u.as_slice(), *c)).collect();
+    let mut graph = make_graph(&umis);
+    for (i, node) in graph.iter_mut().enumerate() {
+        if i % 4 != 3 {
+            node.connections.push(i + 1);
+        }
+    }
+
+    let grouping = determine_umi(&graph, 2);
+    assert_eq!(grouping.len(), 25_000);
+    assert_eq!(
+        grouping.iter().map(|g| g.nodes.len()).sum::<usize>(),
+        umis.len()
+    );
+    assert!(grouping
+        .iter()
+        .all(|g| g.nodes.len() == 3 || g.nodes.len() == 1));
+}
+
+#[test]
+fn test_bundler() {
+    let header = get_header();
+    let records_raw: Vec<&[u8]> = vec![
+        // A reverse read whose 5' end lies past the reads after it
+        b"SRR2057595.1_ATAAA	16	chr19	4000000	255	1000M	*	0	0	*	*	RX:Z:ATAAA",
+        b"SRR2057595.2_ATAAA	0	chr19	4000010	255	38M	*	0	0	*	*	RX:Z:ATAAA",
+        b"SRR2057595.3_ATAAA	0	chr19	4000500	255	38M	*	0	0	*	*	RX:Z:ATAAA",
+        // Shares a 5' position with read 1
+        b"SRR2057595.4_ATAAA	16	chr19	4000600	255	400M	*	0	0	*	*	RX:Z:ATAAA",
+        // Far enough on to release reads 2 and 3
+        b"SRR2057595.5_ATAAA	0	chr19	4001600	255	38M	*	0	0	*	*	RX:Z:ATAAA",
+        // Soft clipped back to the same position as read 5
+        b"SRR2057595.6_ATAAA	0	chr19	4001610	255	10S28M	*	0	0	*	*	RX:Z:ATAAA",
+        b"SRR2057595.7_ATAAA	0	chr19	4005000	255	38M	*	0	0	*	*	RX:Z:ATAAA",
+        b"SRR2057595.8_ATAAA	0	chrX	100	255	38M	*	0	0	*	*	RX:Z:ATAAA",
+    ];
+    let records: Vec<Result<bam::record::Record, Error>> = records_raw
+        .iter()
+        .map(|&r| bam::record::Record::from_sam(&header, r))
+        .collect();
+
+    let bundles: Vec<Vec<Vec<u8>>> = Bundler::new(records.into_iter(), 1000)
+        .map(|bundle| {
+            let bundle = bundle.unwrap();
+            bundle.records.iter().map(|r| r.qname().to_vec()).collect()
+        })
+        .collect();
+    let expected: Vec<Vec<&[u8]>> = vec![
+        vec![b"SRR2057595.2_ATAAA", b"SRR2057595.3_ATAAA"],
+        vec![
+            b"SRR2057595.1_ATAAA",
+            b"SRR2057595.4_ATAAA",
+            b"SRR2057595.5_ATAAA",
+            b"SRR2057595.6_ATAAA",
+        ],
+        vec![b"SRR2057595.7_ATAAA"],
+        vec![b"SRR2057595.8_ATAAA"],
+    ];
+    assert_eq!(bundles, expected);
+}
+
+#[test]
+fn test_coordinate_sorter() {
+    let header = get_header();
+    let records_raw: Vec<&[u8]> = vec![
+        b"SRR2057595.1_ATAAA	16	chr19	4000000	255	1000M	*	0	0	*	*	RX:Z:ATAAA",
+        b"SRR2057595.2_ATAAA	0	chr19	4000010	255	38M	*	0	0	*	*	RX:Z:ATAAA",
+        b"SRR2057595.3_ATAAA	0	chr19	4000500	255	38M	*	0	0	*	*	RX:Z:ATAAA",
+        b"SRR2057595.4_ATAAA	16	chr19	4000600	255	400M	*	0	0	*	*	RX:Z:ATAAA",
+        b"SRR2057595.5_ATAAA	0	chr19	4001600	255	38M	*	0	0	*	*	RX:Z:ATAAA",
+        b"SRR2057595.6_ATAAA	0	chr19	4001610	255	10S28M	*	0	0	*	*	RX:Z:ATAAA",
+        b"SRR2057595.7_ATAAA	0	chr19	4005000	255	38M	*	0	0	*	*	RX:Z:ATAAA",
+        b"SRR2057595.8_ATAAA	0	chrX	100	255	38M	*	0	0	*	*	RX:Z:ATAAA",
+    ];
+    let records: Vec<Result<bam::record::Record, Error>> = records_raw
+        .iter()
+        .map(|&r| bam::record::Record::from_sam(&header, r))
+        .collect();
+
+    // Hand the bundles over in reverse, as if the last bundle finished processing first
+    let mut bundles: Vec<(usize, Bundle)> = Bundler::new(records.into_iter(), 1000)
+        .map(|bundle| bundle.unwrap())
+        .enumerate()
+        .collect();
+    bundles.reverse();
+    let mut sorter = CoordinateSorter::new();
+    let mut released = ReleaseTracker::new();
+    let mut sorted = vec![];
+    let last = bundles[0].1.released_before;
+    for (i, bundle) in bundles.into_iter() {
+        released.push(i, bundle.released_before);
+        if i > 0 {
+            assert_eq!(released.released_before, (0, i32::MIN));
+        }
+        sorted.extend(sorter.push(i, bundle));
+    }
+    assert_eq!(released.released_before, last);
+    assert_eq!(sorter.released_before, last);
+    // Nothing can be released until the first bundle arrives, and then everything can
+    assert_eq!(sorted.len(), 8);
+    sorted.extend(sorter.finish());
+    let qnames: Vec<&[u8]> = sorted.iter().map(|(r, _)| r.qname()).collect();
+    let expected: Vec<&[u8]> = records_raw
+        .iter()
+        .map(|r| r.split(|&c| c == b'\t').next().unwrap())
+        .collect();
+    assert_eq!(qnames, expected);
+}
+
+#[test]
+fn test_process_bundles_errors() {
+    // More bundles than fit in the queue, so processing is still going when writing stops
+    let path = std::env::temp_dir().join(format!("rumi_test_bundles_{}.sam", std::process::id()));
+    let mut sam = String::from("@HD\tVN:1.0\tSO:coordinate\n@SQ\tSN:chr1\tLN:100000000\n");
+    for i in 0..BUNDLE_QUEUE_SIZE * 2 {
+        sam.push_str(&format!(
+            "read{}\t0\tchr1\t{}\t60\t8M\t*\t0\t0\t*\t*\tRX:Z:ACGT\n",
+            i,
+            i * 1000 + 1
+        ));
+    }
+    std::fs::write(&path, sam).unwrap();
+    let config = Config::builder().bundle_window(10).build().unwrap();
+    let open = || bam::Reader::from_path(&path).unwrap();
+    let keep = |records, _: &Config| -> Result<ProcessedReads, RumiError> {
+        Ok((records, vec![], vec![]))
+    };
+
+    let mut written = 0;
+    let result = process_bundles(open(), &config, keep, |_, _| {
+        written += 1;
+        Ok(())
+    });
+    assert!(result.is_ok());
+    assert_eq!(written, BUNDLE_QUEUE_SIZE * 2);
+
+    let result = process_bundles(open(), &config, keep, |_, _| {
+        Err(RumiError::InvalidConfig(String::from("write")))
+    });
+    match result {
+        Err(RumiError::InvalidConfig(reason)) => assert_eq!(reason, "write"),
+        x => panic!("Expected the write error, got {:?}", x),
+    }
+
+    let fail = |_, _: &Config| -> Result<ProcessedReads, RumiError> {
+        Err(RumiError::InvalidConfig(String::from("process")))
+    };
+    match process_bundles(open(), &config, fail, |_, _| Ok(())) {
+        Err(RumiError::InvalidConfig(reason)) => assert_eq!(reason, "process"),
+        x => panic!("Expected the process error, got {:?}", x),
+    }
+
+    let panic = |_, _: &Config| -> Result<ProcessedReads, RumiError> { panic!("process") };
+    match process_bundles(open(), &config, panic, |_, _| Ok(())) {
+        Err(RumiError::ThreadPanic(message)) => assert_eq!(message, "process"),
+        x => panic!("Expected a panic, got {:?}", x),
+    }
+    std::fs::remove_file(&path).unwrap();
+}
+
+#[test]
+fn test_group_reads_errors() {
+    let header = get_header();
+    let mut config = Config::builder()
+        .invalid_umi(UmiPolicy::Error)
+        .build()
+        .unwrap();
+    let group = |raw: &[u8], config: &Config| {
+        let record = bam::record::Record::from_sam(&header, raw).unwrap();
+        group_reads(vec![record], config)
+    };
+
+    match group(b"SRR2057595.1	0	chr19	4000000	255	26M	*	0	0	*	*", &config) {
+        Err(RumiError::MissingUmi { read }) => assert_eq!(read, "SRR2057595.1"),
+        x => panic!("Expected a missing umi, got {:?}", x.map(|_| ())),
+    }
+    match group(
+        b"SRR2057595.2	0	chr19	4000000	255	26M	*	0	0	*	*	RX:Z:ACGTACGTACGTACGTACGTACGT",
+        &config,
+    ) {
+        Err(RumiError::InvalidUmi { read, umi }) => {
+            assert_eq!(read, "SRR2057595.2");
+            assert_eq!(umi, "ACGTACGTACGTACGTACGTACGT");
+        }
+        x => panic!("Expected an invalid umi, got {:?}", x.map(|_| ())),
+    }
+    match group(
+        b"SRR2057595.3	0	chr19	4000000	255	*	*	0	0	*	*	RX:Z:ACGT",
+        &config,
+    ) {
+        Err(RumiError::MalformedRecord { read, .. }) => assert_eq!(read, "SRR2057595.3"),
+        x => panic!("Expected a malformed record, got {:?}", x.map(|_| ())),
+    }
+
+    config.umi_in_read_id = true;
+    match group(b"SRR2057595.4	0	chr19	4000000	255	26M	*	0	0	*	*", &config) {
+        Err(RumiError::MissingUmi { read }) => assert_eq!(read, "SRR2057595.4"),
+        x => panic!("Expected a missing umi, got {:?}", x.map(|_| ())),
+    }
+    assert!(group(
+        b"SRR2057595.5_ACGT	0	chr19	4000000	255	26M	*	0	0	*	*",
+        &config
+    )
+    .is_ok());
+}
+
+#[test]
+fn test_bundler_unsorted() {
+    let header = get_header();
+    let records_raw: Vec<&[u8]> = vec![
+        b"SRR2057595.1_ATAAA	0	chr19	4000010	255	38M	*	0	0	*	*	RX:Z:ATAAA",
+        b"SRR2057595.2_ATAAA	0	chr19	4000000	255	38M	*	0	0	*	*	RX:Z:ATAAA",
+    ];
+    let records: Vec<Result<bam::record::Record, Error>> = records_raw
+        .iter()
+        .map(|&r| bam::record::Record::from_sam(&header, r))
+        .collect();
+
+    match Bundler::new(records.into_iter(), 1000).next() {
+        Some(Err(RumiError::MalformedRecord { read, .. })) => {
+            assert_eq!(read, "SRR2057595.2_ATAAA")
+        }
+        _ => panic!("Expected an unsorted input to be an error"),
+    }
+}
+
+#[test]
+fn test_bundler_long_soft_clip() {
+    let header = get_header();
+    let records_raw: Vec<&[u8]> = vec![
+        b"SRR2057595.1_ATAAA	0	chr19	4000000	255	38M	*	0	0	*	*	RX:Z:ATAAA",
+        b"SRR2057595.2_ATAAA	0	chr19	4001000	255	38M	*	0	0	*	*	RX:Z:ATAAA",
+        b"SRR2057595.3_ATAAA	0	chr19	4001010	255	50S38M	*	0	0	*	*	RX:Z:ATAAA",
+        b"SRR2057595.4_ATAAA	0	chr19	4001020	255	200S38M	*	0	0	*	*	RX:Z:ATAAA",
+    ];
+    let records: Vec<Result<bam::record::Record, Error>> = records_raw
+        .iter()
+        .map(|&r| bam::record::Record::from_sam(&header, r))
+        .collect();
+
+    // The first read is released before the last, whose 5' end is before it
+    let mut bundler = Bundler::new(records.into_iter(), 100);
+    assert_eq!(bundler.next().unwrap().unwrap().records.len(), 1);
+    match bundler.next() {
+        Some(Err(RumiError::MalformedRecord { read, .. })) => {
+            assert_eq!(read, "SRR2057595.4_ATAAA")
+        }
+        _ => panic!("Expected a soft clip longer than the window to be an error"),
+    }
+}
+
+#[test]
+fn test_umi_policies() {
+    let header = get_header();
+    let records_raw: Vec<&[u8]> = vec![
+        b"SRR2057595.1	0	chr19	4000000	255	26M	*	0	0	*	*	RX:Z:ACGT",
+        b"SRR2057595.2	0	chr19	4000000	255	26M	*	0	0	*	*	RX:Z:ACGN",
+        b"SRR2057595.3	0	chr19	4000000	255	26M	*	0	0	*	*	RX:Z:ACGN",
+        b"SRR2057595.4	0	chr19	4000000	255	26M	*	0	0	*	*",
+        b"SRR2057595.5	0	chr19	4000000	255	26M	*	0	0	*	*	RX:Z:ACGTACGTACGTACGTACGTACGT",
+    ];
+    let records = || -> Vec<bam::record::Record> {
+        records_raw
+            .iter()
+            .map(|&r| bam::record::Record::from_sam(&header, r).unwrap())
+            .collect()
+    };
+    let mut config = Config::builder()
+        .missing_umi(UmiPolicy::Skip)
+        .invalid_umi(UmiPolicy::Skip)
+        .build()
+        .unwrap();
+
+    let (grouped, passthrough, _, stats) = group_reads(records(), &config).unwrap();
+    assert_eq!(grouped.values().map(|umis| umis.len()).sum::<usize>(), 1);
+    assert!(passthrough.is_empty());
+    assert_eq!((stats.missing_umi, stats.invalid_umi), (1, 3));
+
+    config.missing_umi = UmiPolicy::Passthrough;
+    config.invalid_umi = UmiPolicy::Passthrough;
+    let (grouped, passthrough, _, _) = group_reads(records(), &config).unwrap();
+    assert_eq!(grouped.values().map(|umis| umis.len()).sum::<usize>(), 1);
+    let names: Vec<&[u8]> = passthrough.iter().map(|r| r.qname()).collect();
+    let expected: Vec<&[u8]> = vec![
+        b"SRR2057595.2",
+        b"SRR2057595.3",
+        b"SRR2057595.4",
+        b"SRR2057595.5",
+    ];
+    assert_eq!(names, expected);
+
+    // The N umi is one away from ACGT, but stays in its own group. The read without a umi and the
+    // read with a umi too long to encode share a group.
+    config.missing_umi = UmiPolicy::OwnGroup;
+    config.invalid_umi = UmiPolicy::OwnGroup;
+    let (grouped, passthrough, _, _) = group_reads(records(), &config).unwrap();
+    assert!(passthrough.is_empty());
+    let (_, reads) = grouped.into_iter().next().unwrap();
+    let graph = build_network(reads, &config);
+    let mut groups = group_umis(&find_groups(&graph, &config));
+    groups.sort();
+    let expected: Vec<Vec<Vec<u8>>> = vec![
+        vec![b"".to_vec()],
+        vec![b"ACGN".to_vec()],
+        vec![b"ACGT".to_vec()],
+    ];
+    assert_eq!(groups, expected);
+    let mut freqs: Vec<(Vec<u8>, u32)> = graph
+        .iter()
+        .map(|n| (n.umi.decode(), n.freq.freq))
+        .collect();
+    freqs.sort();
+    assert_eq!(
+        freqs,
+        vec![
+            (b"".to_vec(), 2),
+            (b"ACGN".to_vec(), 2),
+            (b"ACGT".to_vec(), 1)
+        ]
+    );
+
+    // By default the N umi is grouped like any other, and a umi too long to encode is an error
+    let config = Config::builder()
+        .missing_umi(UmiPolicy::Skip)
+        .build()
+        .unwrap();
+    assert!(group_reads(records(), &config).is_err());
+    let (grouped, _, _, _) = group_reads(records()[..4].to_vec(), &config).unwrap();
+    let (_, reads) = grouped.into_iter().next().unwrap();
+    let graph = build_network(reads, &config);
+    let groups = find_groups(&graph, &config);
+    assert_eq!(groups.len(), 1);
+    assert_eq!(groups[0].umi.decode(), b"ACGN".to_vec());
+    assert_eq!(groups[0].nodes.len(), 2);
+    assert!(Config::builder()
+        .missing_umi(UmiPolicy::Group)
+        .build()
+        .is_err());
+}
+
+#[test]
+fn test_config_builder() {
+    let config = Config::builder().build().unwrap();
+    assert_eq!(config.umi_tag, "RX");
+    assert_eq!(config.allowed_read_dist, 1);
+    assert_eq!(config.allowed_count_factor, 2);
+    assert_eq!(config.allowed_network_depth, 2);
+    assert_eq!(config.method, GroupingMethod::Directional);
+
+    let config = Config::builder()
+        .allowed_read_dist(2)
+        .method(GroupingMethod::Adjacency)
+        .build()
+        .unwrap();
+    assert_eq!(config.allowed_read_dist, 2);
+    assert_eq!(config.method, GroupingMethod::Adjacency);
+
+    let invalid = vec![
+        Config::builder().allowed_network_depth(0),
+        Config::builder().allowed_count_factor(0),
+        Config::builder().bundle_window(-1),
+        Config::builder().umi_tag("RXX"),
+        Config::builder().input_bam(""),
+        Config::builder().duplex(true).is_paired(true),
+    ];
+    for builder in invalid.into_iter() {
+        match builder.build() {
+            Err(RumiError::InvalidConfig(_)) => {}
+            x => panic!("Expected an invalid config, got {:?}", x),
+        }
+    }
+    // The tag isn't used when the umi is in the read id
+    assert!(Config::builder()
+        .umi_tag("")
+        .umi_in_read_id(true)
+        .build()
+        .is_ok());
+}
+
+#[test]
+fn test_write_stats() {
+    let mut stats = Stats::new();
+    stats.reads_in = 10;
+    stats.reads_out = 4;
+
+    let path = std::env::temp_dir().join(format!("rumi_test_stats_{}.json", std::process::id()));
+    let config = Config::builder()
+        .stats(path.to_str().unwrap())
+        .build()
+        .unwrap();
+    write_stats(&stats, &config).unwrap();
+    let json: serde_json::Value =
+        serde_json::from_str(&std::fs::read_to_string(&path).unwrap()).unwrap();
+    assert_eq!(json["stats"]["reads_in"], 10);
+    assert_eq!(json["stats"]["reads_out"], 4);
+    assert_eq!(json["parameters"]["method"], "directional");
+    assert_eq!(json["parameters"]["allowed_read_dist"], 1);
+
+    let config = Config::builder()
+        .stats(path.to_str().unwrap())
+        .stats_format(StatsFormat::Tsv)
+        .build()
+        .unwrap();
+    write_stats(&stats, &config).unwrap();
+    let tsv = std::fs::read_to_string(&path).unwrap();
+    std::fs::remove_file(&path).unwrap();
+    let lines: Vec<&str> = tsv.lines().collect();
+    assert_eq!(lines[0], "name\tvalue");
+    assert!(lines.contains(&"stats.reads_in\t10"));
+    assert!(lines.contains(&"parameters.umi_tag\tRX"));
+    assert!(lines.contains(&"parameters.invalid_umi\tgroup"));
+}
+
+#[test]
+fn test_umi_stats() {
+    let umis: Vec<(&[u8], u32)> = vec![
+        (b"ATTG", 1),
+        (b"ATTA", 456),
+        (b"ATTT", 2),
+        (b"AGTA", 72),
+        (b"AGTC", 1),
+        (b"AGGA", 90),
+    ];
+    let graph = connect_graph(make_graph(&umis), 1, 2, 100, false);
+    let groups = determine_umi(&graph, 2);
+    let mut umi_stats = UmiStats::new();
+    umi_stats.add_position(&graph, &groups);
+
+    let single = make_graph(&[(b"ATTA", 3)]);
+    let single_groups = determine_umi(&single, 2);
+    let mut other = UmiStats::new();
+    other.add_position(&single, &single_groups);
+    umi_stats.update(other);
+
+    assert_eq!(umi_stats.counts_pre[&1], 2);
+    assert_eq!(umi_stats.counts_pre[&3], 1);
+    assert_eq!(umi_stats.counts_post, btreemap![3 => 1, 90 => 1, 532 => 1]);
+    assert_eq!(umi_stats.group_umis, btreemap![1 => 2, 5 => 1]);
+    assert_eq!(umi_stats.group_reads, btreemap![3 => 1, 90 => 1, 532 => 1]);
+    // 26 mismatches over 15 pairs before grouping, ATTA vs AGGA after
+    assert_eq!(umi_stats.edit_dist_pre, btreemap![Some(1) => 1, None => 1]);
+    assert_eq!(umi_stats.edit_dist_post, btreemap![Some(2) => 1, None => 1]);
+    assert_eq!(umi_stats.per_umi[&b"ATTA".to_vec()].pre, vec![456, 3]);
+    assert_eq!(umi_stats.per_umi[&b"ATTA".to_vec()].post, vec![532, 3]);
+    assert!(umi_stats.per_umi[&b"ATTG".to_vec()].post.is_empty());
+
+    // Only the multi umi positions get a null distance, and it is the same from run to run
+    let null = umi_stats.null_edit_dist(&umi_stats.umis_per_position_pre);
+    assert_eq!(null.get(&None), Some(&1));
+    assert_eq!(null.values().sum::<u64>(), 2);
+    assert_eq!(
+        null,
+        umi_stats.null_edit_dist(&umi_stats.umis_per_position_pre)
+    );
+}
+
+#[test]
+fn test_family_sizes() {
+    let umis: Vec<(&[u8], u32)> = vec![
+        (b"ATTG", 1),
+        (b"ATTA", 456),
+        (b"ATTT", 2),
+        (b"AGTA", 72),
+        (b"AGTC", 1),
+        (b"AGGA", 90),
+    ];
+    let graph = connect_graph(make_graph(&umis), 1, 2, 100, false);
+    let mut stats = Stats::new();
+    stats.add_groups(&determine_umi(&graph, 2));
+    assert_eq!(stats.family_sizes, btreemap![90 => 1, 532 => 1]);
+
+    let mut stats = Stats::new();
+    assert_eq!(stats.duplication_rate(), 0.0);
+    assert_eq!(stats.estimated_library_size(), None);
+
+    // No duplicates means there is nothing to estimate the library size from
+    stats.family_sizes = btreemap![1 => 50];
+    assert_eq!(stats.duplication_rate(), 0.0);
+    assert_eq!(stats.estimated_library_size(), None);
+
+    let mut other = Stats::new();
+    other.family_sizes = btreemap![2 => 25];
+    stats.update(&other);
+    assert_eq!(stats.duplication_rate(), 0.25);
+    assert_eq!(stats.estimated_library_size(), Some(165));
+}
+
+#[test]
+fn test_dedup_family_tags() {
+    let header = get_header();
+    let records_raw: Vec<&[u8]> = vec![
+        b"SRR2057595.142416_TAGTA	0	chr19	61240266	255	26M	*	0	0	*	*	RX:Z:TAGTA",
+        b"SRR2057595.297818_CAGTA	0	chr19	61240266	255	26M	*	0	0	*	*	RX:Z:CAGTA	XF:i:100",
+        b"SRR2057595.324156_CAGTA	0	chr19	61240266	255	26M	*	0	0	*	*	RX:Z:CAGTA",
+        b"SRR2057595.357312_CAGTA	0	chr19	61240266	255	26M	*	0	0	*	*	RX:Z:CAGTA",
+        b"SRR2057595.413242_GGGGG	0	chr19	61240266	255	26M	*	0	0	*	*	RX:Z:GGGGG",
+    ];
+    let records: Vec<bam::record::Record> = records_raw
+        .iter()
+        .map(|&r| bam::record::Record::from_sam(&header, r).unwrap())
+        .collect();
+    let config = Config::builder()
+        .family_size_tag("XF")
+        .umi_count_tag("XU")
+        .build()
+        .unwrap();
+
+    let (grouped, _, _, mut stats) = group_reads(records, &config).unwrap();
+    let (position, reads) = grouped.into_iter().next().unwrap();
+    let mut deduped = dedup(
+        &position,
+        reads,
+        &config,
+        &mut stats,
+        &mut None,
+        &mut vec![],
+    );
+    deduped.sort_by_key(|r| r.qname().to_vec());
+    let tags: Vec<(&[u8], i64, i64)> = deduped
+        .iter()
+        .map(|r| {
+            (
+                r.qname(),
+                r.aux(b"XF").unwrap().integer(),
+                r.aux(b"XU").unwrap().integer(),
+            )
+        })
+        .collect();
+    // Any existing value of the tag is replaced
+    let expected: Vec<(&[u8], i64, i64)> = vec![
+        (b"SRR2057595.297818_CAGTA", 4, 2),
+        (b"SRR2057595.413242_GGGGG", 1, 1),
+    ];
+    assert_eq!(tags, expected);
+
+    assert!(Config::builder().family_size_tag("XFF").build().is_err());
+}
+
+#[test]
+fn test_mark_duplicates() {
+    let header = get_header();
+    let records_raw: Vec<&[u8]> = vec![
+        b"read1	0	chr19	1000	20	8M	*	0	0	*	*	RX:Z:CAGTA",
+        b"read2	1024	chr19	1000	60	8M	*	0	0	*	*	RX:Z:CAGTA",
+        b"read3	0	chr19	1000	60	8M	*	0	0	*	*	RX:Z:TAGTA",
+        b"read4	0	chr19	1000	60	8M	*	0	0	*	*	RX:Z:GGGGG",
+    ];
+    let records: Vec<bam::record::Record> = records_raw
+        .iter()
+        .map(|&r| bam::record::Record::from_sam(&header, r).unwrap())
+        .collect();
+    let config = Config::builder()
+        .mark_duplicates(true)
+        .family_size_tag("XF")
+        .build()
+        .unwrap();
+
+    let (grouped, _, _, mut stats) = group_reads(records, &config).unwrap();
+    let (position, reads) = grouped.into_iter().next().unwrap();
+    let mut marked = mark_duplicates(&position, reads, &config, &mut stats, &mut None);
+    marked.sort_by_key(|r| r.qname().to_vec());
+    let group_id = position.group_id(b"CAGTA");
+    type Marks<'a> = (&'a [u8], bool, &'a [u8], &'a [u8]);
+    let flags: Vec<Marks> = marked
+        .iter()
+        .map(|r| {
+            (
+                r.qname(),
+                r.is_duplicate(),
+                r.aux(b"MI").unwrap().string(),
+                r.aux(b"RX").unwrap().string(),
+            )
+        })
+        .collect();
+    // read2 is kept over read1 for its mapq, and its duplicate flag from the input is cleared
+    let expected: Vec<Marks> = vec![
+        (b"read1", true, group_id.as_bytes(), b"CAGTA"),
+        (b"read2", false, group_id.as_bytes(), b"CAGTA"),
+        (b"read3", true, group_id.as_bytes(), b"CAGTA"),
+        (b"read4", false, b"10:999:+:GGGGG", b"GGGGG"),
+    ];
+    assert_eq!(flags, expected);
+    assert_eq!(marked[1].aux(b"XF").unwrap().integer(), 3);
+    assert!(marked[0].aux(b"XF").is_none());
+    assert_eq!(marked[2].aux(b"OX").unwrap().string(), b"TAGTA");
+
+    assert!(Config::builder()
+        .mark_duplicates(true)
+        .group_only(true)
+        .build()
+        .is_err());
+
+    // Reads that would be dropped are written untouched instead
+    let records: Vec<bam::record::Record> = vec![
+        b"kept	67	chr19	1000	60	8M	=	1200	208	*	*	RX:Z:CAGTA" as &[u8],
+        b"kept	131	chr19	1200	60	8M	=	1000	-208	*	*	RX:Z:CAGTA",
+        b"dup	67	chr19	1000	20	8M	=	1200	208	*	*	RX:Z:CAGTA",
+        b"unmapped	77	chr19	1000	0	*	*	0	0	*	*	RX:Z:CAGTA",
+        b"secondary	323	chr19	1000	0	8M	=	1200	208	*	*	RX:Z:CAGTA",
+        b"supplementary	2115	chr19	1000	0	8M	=	1200	208	*	*	RX:Z:CAGTA",
+        b"no_umi	1091	chr19	1000	60	8M	=	1200	208	*	*",
+        b"chimeric	65	chr19	1000	60	8M	chr1	100	0	*	*	RX:Z:CAGTA",
+        b"chimeric	129	chr1	100	60	8M	chr19	1000	0	*	*	RX:Z:CAGTA",
+        b"mate_unmapped	137	chr19	1000	60	8M	=	1000	0	*	*	RX:Z:CAGTA",
+        b"single	0	chr19	1000	60	8M	*	0	0	*	*	RX:Z:CAGTA",
+    ]
+    .into_iter()
+    .map(|r| bam::record::Record::from_sam(&header, r).unwrap())
+    .collect();
+    let config = Config::builder()
+        .mark_duplicates(true)
+        .is_paired(true)
+        .missing_umi(UmiPolicy::Skip)
+        .build()
+        .unwrap();
+    let (grouped, passthrough, discarded, mut stats) =
+        group_reads(records.clone(), &config).unwrap();
+    assert!(discarded.is_empty());
+    let mut written: Vec<bam::record::Record> = grouped
+        .into_iter()
+        .flat_map(|(position, reads)| {
+            mark_duplicates(&position, reads, &config, &mut stats, &mut None)
+        })
+        .collect();
+    written.extend(passthrough);
+    assert_eq!(written.len(), records.len());
+    let mut duplicates: Vec<&[u8]> = written
+        .iter()
+        .filter(|r| r.is_duplicate())
+        .map(|r| r.qname())
+        .collect();
+    duplicates.sort();
+    assert_eq!(duplicates, vec![b"dup" as &[u8]]);
+
+    // Rare umis dropped by the percentile method are written too
+    let config = Config::builder()
+        .mark_duplicates(true)
+        .method(GroupingMethod::Percentile)
+        .build()
+        .unwrap();
+    let mut records: Vec<bam::record::Record> = (0..200)
+        .map(|i| {
+            let r = format!("common{}	0	chr19	1000	60	8M	*	0	0	*	*	RX:Z:CAGTA", i);
+            bam::record::Record::from_sam(&header, r.as_bytes()).unwrap()
+        })
+        .collect();
+    records.push(
+        bam::record::Record::from_sam(&header, b"rare	0	chr19	1000	60	8M	*	0	0	*	*	RX:Z:GGGGG")
+            .unwrap(),
+    );
+    records.push(
+        bam::record::Record::from_sam(&header, b"rare2	0	chr19	1000	60	8M	*	0	0	*	*	RX:Z:TTTTT")
+            .unwrap(),
+    );
+    let (grouped, _, _, mut stats) = group_reads(records, &config).unwrap();
+    let written: usize = grouped
+        .into_iter()
+        .map(|(position, reads)| {
+            mark_duplicates(&position, reads, &config, &mut stats, &mut None).len()
+        })
+        .sum();
+    assert_eq!(written, 202);
+    assert!(Config::builder()
+        .mark_duplicates(true)
+        .discarded("discarded.bam")
+        .build()
+        .is_err());
+}
+
+#[test]
+fn test_discarded() {
+    let header = get_header();
+    let record = |r: &[u8]| bam::record::Record::from_sam(&header, r).unwrap();
+    let records = vec![
+        record(b"read1	0	chr19	1000	20	8M	*	0	0	*	*	RX:Z:CAGTA"),
+        record(b"read2	0	chr19	1000	60	8M	*	0	0	*	*	RX:Z:CAGTA"),
+        record(b"read3	0	chr19	1000	60	8M	*	0	0	*	*	RX:Z:TAGTA"),
+        record(b"read4	0	chr19	1000	60	8M	*	0	0	*	*"),
+        record(b"read5	4	chr19	1000	60	*	*	0	0	*	*	RX:Z:CAGTA"),
+    ];
+    let config = Config::builder()
+        .discarded("discarded.bam")
+        .missing_umi(UmiPolicy::Skip)
+        .build()
+        .unwrap();
+    let reason = |read: &bam::record::Record| read.aux(b"XD").unwrap().string().to_vec();
+
+    let (grouped, _, mut discarded, mut stats) = group_reads(records.clone(), &config).unwrap();
+    let reasons: Vec<(&[u8], Vec<u8>)> = discarded.iter().map(|r| (r.qname(), reason(r))).collect();
+    assert_eq!(
+        reasons,
+        vec![
+            (b"read4" as &[u8], b"missing_umi".to_vec()),
+            (b"read5", b"unmapped".to_vec())
+        ]
+    );
+
+    // The reads that weren't kept are tagged with the id of the group they were collapsed into,
+    // as is the kept read
+    let (position, reads) = grouped.into_iter().next().unwrap();
+    let kept = dedup(
+        &position,
+        reads,
+        &config,
+        &mut stats,
+        &mut None,
+        &mut discarded,
+    );
+    let group_id = position.group_id(b"CAGTA");
+    assert_eq!(kept.len(), 1);
+    assert_eq!(kept[0].qname(), b"read2");
+    assert_eq!(kept[0].aux(b"MI").unwrap().string(), group_id.as_bytes());
+    let mut duplicates: Vec<(&[u8], Vec<u8>, &[u8])> = discarded[2..]
+        .iter()
+        .map(|r| (r.qname(), reason(r), r.aux(b"MI").unwrap().string()))
+        .collect();
+    duplicates.sort();
+    assert_eq!(
+        duplicates,
+        vec![
+            (
+                b"read1" as &[u8],
+                b"duplicate".to_vec(),
+                group_id.as_bytes()
+            ),
+            (b"read3", b"duplicate".to_vec(), group_id.as_bytes())
+        ]
+    );
+
+    // Nothing is kept for the discarded bam unless one is being written
+    let config = Config::builder()
+        .missing_umi(UmiPolicy::Skip)
+        .build()
+        .unwrap();
+    let (_, _, discarded, _) = group_reads(records, &config).unwrap();
+    assert!(discarded.is_empty());
+
+    // Mates are discarded with their read
+    let config = Config::builder()
+        .is_paired(true)
+        .discarded("discarded.bam")
+        .build()
+        .unwrap();
+    let mut mates = MateBuffer::new(&config, &header);
+    mates.push(
+        record(b"pair	163	chr1	100	60	8M	=	300	208	*	*"),
+        Output::Passthrough,
+    );
+    mates.push(
+        record(b"pair	83	chr1	300	60	8M	=	100	-208	*	*	MI:Z:1	XD:Z:duplicate"),
+        Output::Discarded,
+    );
+    let ready = mates.finish();
+    let outputs: Vec<(u16, Output, &[u8])> = ready
+        .iter()
+        .map(|(r, output)| (r.flags(), *output, r.aux(b"XD").unwrap().string()))
+        .collect();
+    assert_eq!(
+        outputs,
+        vec![
+            (163, Output::Discarded, b"duplicate" as &[u8]),
+            (83, Output::Discarded, b"duplicate")
+        ]
+    );
+}
+
+#[test]
+fn test_consensus() {
+    let header = get_header();
+    let records_raw: Vec<&[u8]> = vec![
+        b"read1_AAAAA	0	chr19	61240266	60	8M	*	0	0	ACGTACGT	IIIIIIII	RX:Z:AAAAA	XF:i:100	MC:Z:8M	HI:i:1	MD:Z:8	NM:i:0",
+        b"read2_AAAAA	0	chr19	61240266	60	8M	*	0	0	ACGTACGA	IIIIIII5	RX:Z:AAAAA",
+        b"read3_AAAAA	0	chr19	61240266	30	8M	*	0	0	ACGTACGT	IIIIIII5	RX:Z:AAAAA",
+        b"read4_AAAAA	0	chr19	61240266	60	4M1D4M	*	0	0	ACGTTTTT	IIIIIIII	RX:Z:AAAAA",
+        b"read5_AAAAC	0	chr19	61240266	50	8M	*	0	0	ACNTACGT	IIIIIIII	RX:Z:AAAAC",
+    ];
+    let records: Vec<bam::record::Record> = records_raw
+        .iter()
+        .map(|&r| bam::record::Record::from_sam(&header, r).unwrap())
+        .collect();
+    let config = Config::builder()
+        .consensus(true)
+        .family_size_tag("XF")
+        .build()
+        .unwrap();
+
+    let (grouped, _, _, mut stats) = group_reads(records, &config).unwrap();
+    assert_eq!(grouped.len(), 1);
+    let (position, reads) = grouped.into_iter().next().unwrap();
+    let reads = consensus(
+        &position,
+        reads,
+        &config,
+        &mut stats,
+        &mut None,
+        &mut vec![],
+    );
+    assert_eq!(reads.len(), 1);
+    let read = &reads[0];
+
+    // read4 has a minority alignment and doesn't vote, and the N in read5 is skipped
+    assert_eq!(read.qname(), b"read1_AAAAA");
+    assert_eq!(read.seq().as_bytes(), b"ACGTACGT".to_vec());
+    assert_eq!(read.qual(), &[93, 93, 93, 93, 93, 93, 93, 80]);
+    assert_eq!(read.cigar().to_string(), "8M");
+    assert_eq!(read.aux(b"RX").unwrap().string(), b"AAAAA");
+    assert_eq!(read.aux(b"cd").unwrap().string(), b"4,4,3,4,4,4,4,4");
+    assert_eq!(read.aux(b"cD").unwrap().integer(), 4);
+    assert_eq!(read.aux(b"cM").unwrap().integer(), 3);
+    assert_eq!(read.aux(b"XF").unwrap().integer(), 5);
+    // Tags that don't depend on the sequence are kept
+    assert_eq!(read.aux(b"MC").unwrap().string(), b"8M");
+    assert_eq!(read.aux(b"HI").unwrap().integer(), 1);
+    assert!(read.aux(b"MD").is_none());
+    assert!(read.aux(b"NM").is_none());
+
+    // When paired, the consensus keeps the mate of the read it was built on as it is
+    let records: Vec<bam::record::Record> = vec![
+        b"pairA	99	chr19	1000	60	8M	=	1200	208	ACGTACGT	IIIIIIII	RX:Z:AAAAA" as &[u8],
+        b"pairB	99	chr19	1000	30	8M	=	1200	208	ACGTACGA	IIIIIIII	RX:Z:AAAAA",
+        b"pairC	99	chr19	1000	30	8M	=	1200	208	ACGTACGA	IIIIIIII	RX:Z:AAAAA",
+        b"pairA	147	chr19	1200	60	8M	=	1000	-208	TTTTTTTT	IIIIIIII	RX:Z:AAAAA",
+        b"pairB	147	chr19	1200	30	8M	=	1000	-208	GGGGGGGG	IIIIIIII	RX:Z:AAAAA",
+        b"pairC	147	chr19	1200	30	8M	=	1000	-208	GGGGGGGG	IIIIIIII	RX:Z:AAAAA",
+    ]
+    .into_iter()
+    .map(|r| bam::record::Record::from_sam(&header, r).unwrap())
+    .collect();
+    let paired = Config::builder()
+        .consensus(true)
+        .is_paired(true)
+        .family_size_tag("XF")
+        .build()
+        .unwrap();
+    let (grouped, passthrough, _, mut stats) = group_reads(records, &paired).unwrap();
+    let (position, reads) = grouped.into_iter().next().unwrap();
+    let reads = consensus(
+        &position,
+        reads,
+        &paired,
+        &mut stats,
+        &mut None,
+        &mut vec![],
+    );
+    let mut mates = MateBuffer::new(&paired, &header);
+    for read in reads {
+        mates.push(read, Output::Grouped);
+    }
+    for mate in passthrough {
+        mates.push(mate, Output::Passthrough);
+    }
+    let written: Vec<(Vec<u8>, Vec<u8>, i64)> = mates
+        .finish()
+        .into_iter()
+        .map(|(r, _)| {
+            let size = r.aux(b"XF").unwrap().integer();
+            (r.qname().to_vec(), r.seq().as_bytes(), size)
+        })
+        .collect();
+    assert_eq!(
+        written,
+        vec![
+            (b"pairA".to_vec(), b"ACGTACGA".to_vec(), 3),
+            (b"pairA".to_vec(), b"TTTTTTTT".to_vec(), 3),
+        ]
+    );
+
+    // Reads without qualities barely count, rather than voting as Q93
+    let records: Vec<bam::record::Record> = vec![
+        b"read1	0	chr19	61240266	60	8M	*	0	0	ACGTACGT	*	RX:Z:AAAAA" as &[u8],
+        b"read2	0	chr19	61240266	60	8M	*	0	0	ACGTACGT	*	RX:Z:AAAAA",
+        b"read3	0	chr19	61240266	60	8M	*	0	0	ACGTACGA	IIIIIIII	RX:Z:AAAAA",
+    ]
+    .into_iter()
+    .map(|r| bam::record::Record::from_sam(&header, r).unwrap())
+    .collect();
+    let (grouped, _, _, mut stats) = group_reads(records, &config).unwrap();
+    let (position, reads) = grouped.into_iter().next().unwrap();
+    let reads = consensus(
+        &position,
+        reads,
+        &config,
+        &mut stats,
+        &mut None,
+        &mut vec![],
+    );
+    assert_eq!(reads[0].seq().as_bytes(), b"ACGTACGA".to_vec());
+    assert_eq!(reads[0].qual(), &[44, 44, 44, 44, 44, 44, 44, 36]);
+
+    assert!(Config::builder()
+        .consensus(true)
+        .group_only(true)
+        .build()
+        .is_err());
+}
+
+#[test]
+fn test_duplex() {
+    assert_eq!(
+        duplex_umi(b"AAAA-CCCC"),
+        Some((b"AAAACCCC".to_vec(), DuplexStrand::A))
+    );
+    assert_eq!(
+        duplex_umi(b"CCCC-AAAA"),
+        Some((b"AAAACCCC".to_vec(), DuplexStrand::B))
+    );
+    assert_eq!(duplex_umi(b"AAAACCCC"), None);
+    assert_eq!(duplex_umi(b"AAAA-"), None);
+    assert_eq!(duplex_umi(b"AA-CC-GG"), None);
+
+    let header = get_header();
+    let records_raw: Vec<&[u8]> = vec![
+        b"read1	0	chr19	61240266	60	8M	*	0	0	ACGTACGT	++++++++	RX:Z:AAAA-CCCC",
+        b"read2	0	chr19	61240266	60	8M	*	0	0	ACGTACGA	++++++++	RX:Z:AAAA-CCCC",
+        b"read3	0	chr19	61240266	60	8M	*	0	0	ACGTACGT	++++++++	RX:Z:CCCC-AAAA",
+        b"read4	0	chr19	61240266	60	8M	*	0	0	ACGAACGT	++++++++	RX:Z:CCCC-AAAA",
+        b"read5	0	chr19	61240266	60	8M	*	0	0	ACGTACGT	++++++++	RX:Z:GGGG",
+    ];
+    let records: Vec<bam::record::Record> = records_raw
+        .iter()
+        .map(|&r| bam::record::Record::from_sam(&header, r).unwrap())
+        .collect();
+    let config = Config::builder()
+        .duplex(true)
+        .consensus(true)
+        .invalid_umi(UmiPolicy::OwnGroup)
+        .build()
+        .unwrap();
+    assert_eq!(duplex_strand(&records[0], &config), Some(DuplexStrand::A));
+    assert_eq!(duplex_strand(&records[2], &config), Some(DuplexStrand::B));
+    assert_eq!(duplex_strand(&records[4], &config), None);
+
+    // Both strands share a umi, and the umi without halves is in its own group
+    let (grouped, _, _, mut stats) = group_reads(records, &config).unwrap();
+    assert_eq!(stats.invalid_umi, 1);
+    let (position, reads) = grouped.into_iter().next().unwrap();
+    assert!(reads.contains_key(&BaseBits::new(b"AAAACCCC").unwrap()));
+    let mut reads = consensus(
+        &position,
+        reads,
+        &config,
+        &mut stats,
+        &mut None,
+        &mut vec![],
+    );
+    assert_eq!(reads.len(), 2);
+    reads.sort_by_key(|r| r.qname().to_vec());
+
+    // Each strand has a tie at one offset, and the other strand can't make up for it
+    let read = &reads[0];
+    assert_eq!(read.qname(), b"read1");
+    assert_eq!(read.seq().as_bytes(), b"ACGNACGN".to_vec());
+    assert_eq!(read.qual(), &[40, 40, 40, 2, 40, 40, 40, 2]);
+    assert_eq!(read.aux(b"aD").unwrap().integer(), 2);
+    assert_eq!(read.aux(b"bD").unwrap().integer(), 2);
+    assert_eq!(read.aux(b"cD").unwrap().integer(), 4);
+    assert_eq!(reads[1].qname(), b"read5");
+    assert!(reads[1].aux(b"aD").is_none());
+}
+
+#[test]
+fn test_group_tags() {
+    let header = get_header();
+    let records_raw: Vec<&[u8]> = vec![
+        b"read1	0	chr19	61240266	60	8M	*	0	0	*	*	RX:Z:ATAAA	BX:Z:ACGT-1",
+        b"read2	0	chr19	61240266	60	8M	*	0	0	*	*	RX:Z:ATAAA",
+        b"read3	0	chr19	61240266	60	8M	*	0	0	*	*	RX:Z:ATAAT	BX:Z:ACGT-1",
+        b"read4	16	chr19	61240266	60	8M	*	0	0	*	*	RX:Z:ATAAA",
+    ];
+    let records: Vec<bam::record::Record> = records_raw
+        .iter()
+        .map(|&r| bam::record::Record::from_sam(&header, r).unwrap())
+        .collect();
+    let config = Config::builder().group_only(true).build().unwrap();
+
+    let (grouped, _, _, mut stats) = group_reads(records, &config).unwrap();
+    let mut reads: Vec<bam::record::Record> = grouped
+        .into_iter()
+        .flat_map(|(position, reads)| {
+            label_groups(
+                &position,
+                reads,
+                &config,
+                &mut stats,
+                &mut None,
+                &mut vec![],
+            )
+        })
+        .flatten()
+        .collect();
+    reads.sort_by_key(|r| r.qname().to_vec());
+    type Tags<'a> = (&'a [u8], &'a [u8], &'a [u8], Option<&'a [u8]>);
+    let tags: Vec<Tags> = reads
+        .iter()
+        .map(|r| {
+            (
+                r.aux(b"MI").unwrap().string(),
+                r.aux(b"RX").unwrap().string(),
+                r.aux(b"OX").unwrap().string(),
+                r.aux(b"BX").map(|bx| bx.string()),
+            )
+        })
+        .collect();
+    // Ids are made from the position and corrected umi, and BX is left alone
+    let expected: Vec<Tags> = vec![
+        (b"10:61240265:+:ATAAA", b"ATAAA", b"ATAAA", Some(b"ACGT-1")),
+        (b"10:61240265:+:ATAAA", b"ATAAA", b"ATAAA", None),
+        (b"10:61240265:+:ATAAA", b"ATAAA", b"ATAAT", Some(b"ACGT-1")),
+        (b"10:61240273:-:ATAAA", b"ATAAA", b"ATAAA", None),
+    ];
+    assert_eq!(tags, expected);
+
+    // Duplex umis are written back in the order of each read's halves
+    let duplex = Config::builder().duplex(true).build().unwrap();
+    let mut top = bam::record::Record::from_sam(
+        &header,
+        b"top	0	chr19	61240266	60	8M	*	0	0	*	*	RX:Z:AAAT-CCCC",
+    )
+    .unwrap();
+    let mut bottom = bam::record::Record::from_sam(
+        &header,
+        b"bottom	0	chr19	61240266	60	8M	*	0	0	*	*	RX:Z:CCCC-AAAA",
+    )
+    .unwrap();
+    assert_eq!(
+        orient_umi(&top, b"AAAACCCC", &duplex),
+        b"AAAA-CCCC".to_vec()
+    );
+    assert_eq!(
+        orient_umi(&bottom, b"AAAACCCC", &duplex),
+        b"CCCC-AAAA".to_vec()
+    );
+    set_group_id(&mut top, "3", &duplex);
+    set_group_id(&mut bottom, "3", &duplex);
+    assert_eq!(top.aux(b"MI").unwrap().string(), b"3/A");
+    assert_eq!(bottom.aux(b"MI").unwrap().string(), b"3/B");
+
+    assert!(Config::builder().group_tag("RX").build().is_err());
+    assert!(Config::builder().original_umi_tag("O").build().is_err());
+}
+
+#[test]
+fn test_mate_buffer() {
+    let header = get_header();
+    let record = |r: &[u8]| bam::record::Record::from_sam(&header, r).unwrap();
+    let config = Config::builder()
+        .is_paired(true)
+        .family_size_tag("XF")
+        .build()
+        .unwrap();
+    let mut mates = MateBuffer::new(&config, &header);
+
+    // kept's mate comes first and holds back everything after it until kept is written
+    mates.push(
+        record(b"kept	163	chr1	100	60	8M	=	300	208	*	*"),
+        Output::Passthrough,
+    );
+    mates.push(
+        record(b"other	99	chr1	150	60	8M	=	400	258	*	*	XF:i:1"),
+        Output::Grouped,
+    );
+    mates.push(
+        record(b"dup	163	chr1	200	60	8M	=	350	158	*	*"),
+        Output::Passthrough,
+    );
+    mates.expire((0, 250));
+    assert!(mates.ready().is_empty());
+    mates.push(
+        record(b"kept	83	chr1	300	60	8M	=	100	-208	*	*	XF:i:3"),
+        Output::Grouped,
+    );
+    let ready = mates.ready();
+    let names: Vec<&[u8]> = ready.iter().map(|(r, _)| r.qname()).collect();
+    assert_eq!(names, vec![b"kept" as &[u8], b"other"]);
+    assert_eq!(ready[0].0.aux(b"XF").unwrap().integer(), 3);
+
+    // dup's read was never written, so once the input is past it so is dup
+    mates.expire((0, 351));
+    let ready = mates.ready();
+    let names: Vec<&[u8]> = ready.iter().map(|(r, _)| r.qname()).collect();
+    assert_eq!(names, vec![b"kept" as &[u8]]);
+
+    // other's mate comes after it, and anything left waiting at the end is dropped
+    mates.push(
+        record(b"other	147	chr1	400	60	8M	=	150	-258	*	*"),
+        Output::Passthrough,
+    );
+    mates.push(
+        record(b"orphan	147	chr1	500	60	8M	=	450	-58	*	*"),
+        Output::Passthrough,
+    );
+    let ready = mates.finish();
+    let names: Vec<&[u8]> = ready.iter().map(|(r, _)| r.qname()).collect();
+    assert_eq!(names, vec![b"other" as &[u8]]);
+    assert_eq!(ready[0].0.aux(b"XF").unwrap().integer(), 1);
+}
+
+#[test]
+fn test_alignment_policies() {
+    let header = get_header();
+    let record = |r: &[u8]| bam::record::Record::from_sam(&header, r).unwrap();
+    let config = Config::builder()
+        .secondary(AlignmentPolicy::Passthrough)
+        .build()
+        .unwrap();
+    let records = vec![
+        record(b"read	0	chr1	100	60	8M	*	0	0	*	*	RX:Z:ACGT"),
+        record(b"read	256	chr1	100	60	8M	*	0	0	*	*	RX:Z:ACGT"),
+        record(b"read	2048	chr1	100	60	8M	*	0	0	*	*	RX:Z:ACGT"),
+    ];
+    let (grouped, passthrough, _, stats) = group_reads(records, &config).unwrap();
+    assert_eq!(grouped.len(), 1);
+    assert_eq!(passthrough.len(), 1);
+    assert!(passthrough[0].is_secondary());
+    assert_eq!((stats.secondary, stats.supplementary), (1, 1));
+
+    let config = Config::builder()
+        .secondary(AlignmentPolicy::Follow)
+        .supplementary(AlignmentPolicy::Follow)
+        .family_size_tag("XF")
+        .build()
+        .unwrap();
+    let mut mates = MateBuffer::new(&config, &header);
+    let names = |ready: &[(bam::record::Record, Output)]| -> Vec<(Vec<u8>, u16)> {
+        ready
+            .iter()
+            .map(|(r, _)| (r.qname().to_vec(), r.flags()))
+            .collect()
+    };
+
+    // A supplementary alignment before its primary holds back everything after it until the
+    // primary is written, and then gets its tags
+    mates.push(
+        record(b"split	2048	chr1	100	60	8M	*	0	0	*	*	SA:Z:chr1,300,+,8M,60,0;"),
+        Output::Passthrough,
+    );
+    mates.push(
+        record(b"other	0	chr1	150	60	8M	*	0	0	*	*	XF:i:1"),
+        Output::Grouped,
+    );
+    mates.expire((0, 250));
+    assert!(mates.ready().is_empty());
+    mates.push(
+        record(b"split	0	chr1	300	60	8M	*	0	0	*	*	SA:Z:chr1,100,+,8M,60,0;	XF:i:2"),
+        Output::Grouped,
+    );
+    let ready = mates.ready();
+    assert_eq!(
+        names(&ready),
+        vec![
+            (b"split".to_vec(), 2048),
+            (b"other".to_vec(), 0),
+            (b"split".to_vec(), 0)
+        ]
+    );
+    assert_eq!(ready[0].0.aux(b"XF").unwrap().integer(), 2);
+
+    // Once the input is past a primary that wasn't written, its alignments are dropped
+    mates.push(
+        record(b"dup	2048	chr1	350	60	8M	*	0	0	*	*	SA:Z:chr1,320,+,8M,60,0;"),
+        Output::Passthrough,
+    );
+    mates.expire((0, 400));
+    assert!(mates.ready().is_empty());
+
+    // Alignments after a written primary follow it straight away, whether or not it says that
+    // there are more alignments
+    mates.push(
+        record(b"multi	0	chr1	400	60	8M	*	0	0	*	*	XF:i:4"),
+        Output::Grouped,
+    );
+    mates.push(
+        record(b"multi	256	chr1	450	0	8M	*	0	0	*	*"),
+        Output::Passthrough,
+    );
+    let ready = mates.ready();
+    assert_eq!(
+        names(&ready),
+        vec![(b"multi".to_vec(), 0), (b"multi".to_vec(), 256)]
+    );
+    assert_eq!(ready[1].0.aux(b"XF").unwrap().integer(), 4);
+
+    // A secondary alignment can't tell where its primary is, so it waits for it for
+    // FOLLOW_WINDOW, and is dropped if it isn't written by then
+    mates.push(
+        record(b"lost	256	chr1	500	0	8M	*	0	0	*	*"),
+        Output::Passthrough,
+    );
+    mates.push(record(b"last	0	chr1	600	60	8M	*	0	0	*	*"), Output::Grouped);
+    mates.expire((0, 700));
+    assert!(mates.ready().is_empty());
+    mates.expire((0, 500 + FOLLOW_WINDOW));
+    assert_eq!(names(&mates.ready()), vec![(b"last".to_vec(), 0)]);
+
+    // Decided reads are forgotten once the input is FOLLOW_WINDOW past them
+    mates.push(
+        record(b"last	256	chr1	700	0	8M	*	0	0	*	*"),
+        Output::Passthrough,
+    );
+    assert_eq!(names(&mates.ready()), vec![(b"last".to_vec(), 256)]);
+    mates.expire((1, 0));
+    mates.push(
+        record(b"last	256	chr2	100	0	8M	*	0	0	*	*"),
+        Output::Passthrough,
+    );
+    assert!(mates.ready().is_empty());
+    assert!(mates.finish().is_empty());
+}
+
+#[test]
+fn test_pair_key() {
+    let header = get_header();
+    let records_raw: Vec<&[u8]> = vec![
+        b"pairA	99	chr19	1000	60	8M	=	1100	108	*	*	RX:Z:ACGT	MC:Z:8M",
+        b"pairB	99	chr19	1000	60	8M	=	1100	106	*	*	RX:Z:ACGT	MC:Z:6M2S",
+        b"pairC	99	chr19	1000	60	8M	=	1101	109	*	*	RX:Z:ACGT	MC:Z:8M",
+        b"pairD	99	chr19	1000	60	8M	=	1102	108	*	*	RX:Z:ACGT	MC:Z:2S6M",
+    ];
+    let records: Vec<bam::record::Record> = records_raw
+        .iter()
+        .map(|&r| bam::record::Record::from_sam(&header, r).unwrap())
+        .collect();
+
+    let group = |key| {
+        let config = Config::builder()
+            .is_paired(true)
+            .pair_key(key)
+            .build()
+            .unwrap();
+        let (grouped, _, _, _) = group_reads(records.clone(), &config).unwrap();
+        let mut positions: Vec<Vec<u32>> = grouped
+            .values()
+            .map(|umis| umis.values().map(|freq| freq.freq).collect())
+            .collect();
+        positions.sort();
+        positions
+    };
+    // The soft clip on pairB's mate changes its tlen but not where its 5' end is, and pairD's
+    // mate starts later but has the same 5' end, since it is reversed
+    assert_eq!(group(PairKey::Tlen), vec![vec![1], vec![1], vec![2]]);
+    assert_eq!(group(PairKey::Template), vec![vec![1], vec![3]]);
+
+    let config = Config::builder()
+        .is_paired(true)
+        .pair_key(PairKey::Template)
+        .build()
+        .unwrap();
+    let position = Position::new(&records[1], &config);
+    assert_eq!(position.group_id(b"ACGT"), "10:999:+:m10:1107-:ACGT");
+}
+
+#[test]
+fn test_chimeric_pairs() {
+    let header = get_header();
+    let records_raw: Vec<&[u8]> = vec![
+        b"pairB	97	chr1	1000	60	8M	chr2	5000	0	*	*	RX:Z:ACGT	MC:Z:8M",
+        b"pairA	97	chr1	1000	10	8M	chr2	5000	0	*	*	RX:Z:ACGT	MC:Z:8M",
+        b"pairC	97	chr1	1000	60	8M	chr3	5000	0	*	*	RX:Z:ACGT	MC:Z:8M",
+        b"pairA	257	chr1	1000	60	8M	chr2	5000	0	*	*	RX:Z:ACGT	MC:Z:8M",
+        b"pairD	2145	chr1	1000	60	8M	chr2	5000	0	*	*	RX:Z:ACGT	MC:Z:8M",
+        b"pairA	145	chr2	5000	60	8M	chr1	1000	0	*	*	RX:Z:ACGT	MC:Z:8M",
+        b"pairB	145	chr2	5000	60	8M	chr1	1000	0	*	*	RX:Z:ACGT	MC:Z:8M",
+    ];
+    let records: Vec<bam::record::Record> = records_raw
+        .iter()
+        .map(|&r| bam::record::Record::from_sam(&header, r).unwrap())
+        .collect();
+
+    let config = Config::builder().is_paired(true).build().unwrap();
+    let (grouped, passthrough, _, stats) = group_reads(records.clone(), &config).unwrap();
+    assert!(grouped.is_empty());
+    assert!(passthrough.is_empty());
+    assert_eq!(stats.chimeric, 3);
+    assert_eq!(stats.secondary, 1);
+    assert_eq!(stats.supplementary, 1);
+
+    // Both ends are grouped, and keep the same pair even though its mapq is lower at one end
+    let config = Config::builder()
+        .is_paired(true)
+        .keep_chimeric(true)
+        .build()
+        .unwrap();
+    let (grouped, passthrough, _, mut stats) = group_reads(records, &config).unwrap();
+    assert!(passthrough.is_empty());
+    assert_eq!(grouped.len(), 3);
+    let mut kept: Vec<(String, Vec<u8>, i32)> = grouped
+        .into_iter()
+        .flat_map(|(position, umis)| {
+            let id = position.group_id(b"ACGT");
+            dedup(&position, umis, &config, &mut stats, &mut None, &mut vec![])
+                .into_iter()
+                .map(move |read| (id.clone(), read.qname().to_vec(), read.tid()))
+        })
+        .collect();
+    kept.sort();
+    let expected: Vec<(String, Vec<u8>, i32)> = vec![
+        (String::from("0:999:+:m11:5007-:ACGT"), b"pairA".to_vec(), 0),
+        (
+            String::from("0:999:+:m11:5007-:ACGT"),
+            b"pairA".to_vec(),
+            11,
+        ),
+        (String::from("0:999:+:m12:5007-:ACGT"), b"pairC".to_vec(), 0),
+    ];
+    assert_eq!(kept, expected);
+
+    // Spliced reads are grouped apart from the rest, so their ids have the splice too
+    let spliced = bam::record::Record::from_sam(
+        &header,
+        b"pairE	97	chr1	1000	60	4M100N4M	chr2	5000	0	*	*	RX:Z:ACGT	MC:Z:8M",
+    )
+    .unwrap();
+    assert_eq!(
+        Position::new(&spliced, &config).group_id(b"ACGT"),
+        "0:999:+:s4:m11:5007-:ACGT"
+    );
+}
+
+#[test]
+fn test_per_cell_gene() {
+    let header = get_header();
+    let record = |r: &[u8]| bam::record::Record::from_sam(&header, r).unwrap();
+    let records = vec![
+        record(b"read1	0	chr19	1000	60	8M	*	0	0	*	*	RX:Z:CAGTA	CB:Z:AAAC	XT:Z:GENE1"),
+        record(b"read2	0	chr19	1000	60	8M	*	0	0	*	*	RX:Z:CAGTA	CB:Z:GGGT	XT:Z:GENE1"),
+        record(b"read3	16	chr19	1200	60	8M	*	0	0	*	*	RX:Z:CAGTA	CB:Z:AAAC	XT:Z:GENE1"),
+        record(b"read4	0	chr19	1000	60	8M	*	0	0	*	*	RX:Z:CAGTA	XT:Z:GENE1"),
+        record(b"read5	0	chr19	1000	60	8M	*	0	0	*	*	RX:Z:CAGTA	CB:Z:AAAC	XT:Z:__no_feature"),
+    ];
+    let sizes = |grouped: &ReadMap| -> Vec<u32> {
+        grouped
+            .values()
+            .map(|umis| umis.values().map(|r| r.freq).sum())
+            .collect()
+    };
+
+    // Reads at the same position are split by cell
+    let config = Config::builder().per_cell(true).build().unwrap();
+    let (grouped, _, _, stats) = group_reads(records.clone(), &config).unwrap();
+    assert_eq!(stats.missing_cell, 1);
+    assert_eq!(sizes(&grouped), vec![2, 1, 1]);
+    let ids: Vec<String> = grouped.keys().map(|p| p.group_id(b"CAGTA")).collect();
+    assert_eq!(
+        ids,
+        vec![
+            "10:999:+:cAAAC:CAGTA",
+            "10:999:+:cGGGT:CAGTA",
+            "10:1207:-:cAAAC:CAGTA"
+        ]
+    );
+
+    // Reads of a gene are grouped wherever they start
+    let config = Config::builder()
+        .per_cell(true)
+        .per_gene(true)
+        .discarded("discarded.bam")
+        .build()
+        .unwrap();
+    let (grouped, _, discarded, stats) = group_reads(records.clone(), &config).unwrap();
+    assert_eq!((stats.missing_cell, stats.missing_gene), (1, 1));
+    assert_eq!(sizes(&grouped), vec![2, 1]);
+    let ids: Vec<String> = grouped.keys().map(|p| p.group_id(b"CAGTA")).collect();
+    assert_eq!(ids, vec!["10:gGENE1:cAAAC:CAGTA", "10:gGENE1:cGGGT:CAGTA"]);
+    let reasons: Vec<&[u8]> = discarded
+        .iter()
+        .map(|r| r.aux(b"XD").unwrap().string())
+        .collect();
+    assert_eq!(reasons, vec![b"missing_cell" as &[u8], b"missing_gene"]);
+
+    // Without the cell the gene groups all of its reads
+    let config = Config::builder().per_gene(true).build().unwrap();
+    let (grouped, _, _, _) = group_reads(records, &config).unwrap();
+    assert_eq!(sizes(&grouped), vec![4]);
+
+    // Consensus reads keep the cell and gene they were grouped by
+    let records = vec![
+        record(b"read1	0	chr19	1000	60	8M	*	0	0	ACGTACGT	IIIIIIII	RX:Z:CAGTA	CB:Z:AAAC	XT:Z:GENE1"),
+        record(b"read2	0	chr19	1000	50	8M	*	0	0	ACGTACGT	IIIIIIII	RX:Z:CAGTA	CB:Z:AAAC	XT:Z:GENE1"),
+    ];
+    let config = Config::builder()
+        .per_cell(true)
+        .per_gene(true)
+        .consensus(true)
+        .build()
+        .unwrap();
+    let (grouped, _, _, mut stats) = group_reads(records, &config).unwrap();
+    let (position, reads) = grouped.into_iter().next().unwrap();
+    let reads = consensus(
+        &position,
+        reads,
+        &config,
+        &mut stats,
+        &mut None,
+        &mut vec![],
+    );
+    assert_eq!(reads.len(), 1);
+    assert_eq!(reads[0].aux(b"CB").unwrap().string(), b"AAAC");
+    assert_eq!(reads[0].aux(b"XT").unwrap().string(), b"GENE1");
+}
+
+#[test]
+fn test_count() {
+    let header = get_header();
+    let record = |r: &[u8]| bam::record::Record::from_sam(&header, r).unwrap();
+    let records = vec![
+        record(b"read1	0	chr19	1000	60	8M	*	0	0	*	*	RX:Z:CAGTA	CB:Z:AAAC	XT:Z:GENE1"),
+        record(b"read2	16	chr19	1200	60	8M	*	0	0	*	*	RX:Z:CAGTT	CB:Z:AAAC	XT:Z:GENE1"),
+        record(b"read3	0	chr19	1000	60	8M	*	0	0	*	*	RX:Z:GGGGG	CB:Z:AAAC	XT:Z:GENE1"),
+        record(b"read4	0	chr19	1000	60	8M	*	0	0	*	*	RX:Z:CAGTA	CB:Z:GGGT	XT:Z:GENE1"),
+        record(b"read5	0	chr19	5000	60	8M	*	0	0	*	*	RX:Z:CAGTA	CB:Z:GGGT	XT:Z:GENE2"),
+    ];
+
+    // The umis one apart are one molecule, wherever in the gene their reads start
+    let config = Config::builder()
+        .per_gene(true)
+        .per_cell(true)
+        .build()
+        .unwrap();
+    let (counts, stats) = count_molecules(records.clone(), &config).unwrap();
+    let counts: Vec<(&[u8], &[u8], u32)> = counts
+        .iter()
+        .map(|((gene, cell), &count)| (gene.as_slice(), cell.as_slice(), count))
+        .collect();
+    assert_eq!(
+        counts,
+        vec![
+            (b"GENE1" as &[u8], b"AAAC" as &[u8], 2),
+            (b"GENE1", b"GGGT", 1),
+            (b"GENE2", b"GGGT", 1)
+        ]
+    );
+    assert_eq!(stats.family_sizes, btreemap![1 => 3, 2 => 1]);
+
+    let dir = std::env::temp_dir().join(format!("rumi_test_count_{}", std::process::id()));
+    let config = Config::builder()
+        .per_gene(true)
+        .per_cell(true)
+        .count_format(CountFormat::Mtx)
+        .count_output(dir.to_str().unwrap())
+        .build()
+        .unwrap();
+    let (counts, _) = count_molecules(records.clone(), &config).unwrap();
+    write_counts(&counts, &config).unwrap();
+    let read = |name: &str| std::fs::read_to_string(dir.join(name)).unwrap();
+    assert_eq!(
+        read("matrix.mtx"),
+        "%%MatrixMarket matrix coordinate integer general\n2 2 3\n1 1 2\n1 2 1\n2 2 1\n"
+    );
+    assert_eq!(read("barcodes.tsv"), "AAAC\nGGGT\n");
+    assert_eq!(
+        read("features.tsv"),
+        "GENE1\tGENE1\tGene Expression\nGENE2\tGENE2\tGene Expression\n"
+    );
+    std::fs::remove_dir_all(&dir).unwrap();
+
+    // Without cells each gene is counted once, and there are no cells to make a matrix of
+    let path = std::env::temp_dir().join(format!("rumi_test_count_{}.tsv", std::process::id()));
+    let config = Config::builder()
+        .per_gene(true)
+        .count_output(path.to_str().unwrap())
+        .build()
+        .unwrap();
+    let (counts, _) = count_molecules(records, &config).unwrap();
+    write_counts(&counts, &config).unwrap();
+    assert_eq!(
+        std::fs::read_to_string(&path).unwrap(),
+        "gene\tcount\nGENE1\t2\nGENE2\t1\n"
+    );
+    std::fs::remove_file(&path).unwrap();
+    assert!(Config::builder()
+        .count_format(CountFormat::Mtx)
+        .build()
+        .is_err());
+}
+
+#[test]
+fn test_cell_whitelist() {
+    let whitelist =
+        CellWhitelist::new(&[b"AAAAAAAA", b"CCCCCCCC", b"AAAACCCC", b"AAAACCGG"]).unwrap();
+    assert_eq!(
+        whitelist.correct(b"AAAACCCC", None),
+        Some(b"AAAACCCC".to_vec())
+    );
+    assert_eq!(
+        whitelist.correct(b"AAAAAAAT", None),
+        Some(b"AAAAAAAA".to_vec())
+    );
+    assert_eq!(whitelist.correct(b"AAAAAATT", None), None);
+    assert_eq!(
+        whitelist.correct(b"AAAAAAAN", None),
+        Some(b"AAAAAAAA".to_vec())
+    );
+    assert_eq!(
+        whitelist.correct(b"AAAACCCN", None),
+        Some(b"AAAACCCC".to_vec())
+    );
+    assert_eq!(whitelist.correct(b"NAAAAAAN", None), None);
+    assert_eq!(whitelist.correct(b"AAAAAAA", None), None);
+
+    // AAAACCCG is one from AAAACCCC at its last base, and from AAAACCGG at the one before
+    assert_eq!(whitelist.correct(b"AAAACCCG", None), None);
+    assert_eq!(
+        whitelist.correct(b"AAAACCCG", Some(b"IIIIII#I")),
+        Some(b"AAAACCGG".to_vec())
+    );
+    assert_eq!(
+        whitelist.correct(b"AAAACCCG", Some(b"IIIIIII#")),
+        Some(b"AAAACCCC".to_vec())
+    );
+    // Barcodes one from the same base can't be told apart
+    let whitelist = CellWhitelist::new(&[b"AAAACCCC", b"AAAACCGG", b"AAAACCCA"]).unwrap();
+    assert_eq!(whitelist.correct(b"AAAACCCG", Some(b"IIIIIII#")), None);
+    assert_eq!(whitelist.correct(b"AAAACCCN", None), None);
+
+    assert!(CellWhitelist::new(&[b"AAAA", b"AAAAA"]).is_err());
+    assert!(CellWhitelist::new(&[b"AANA"]).is_err());
+    assert!(CellWhitelist::new(&[]).is_err());
+
+    // Reads are grouped by their corrected barcode, which is written to the cell tag
+    let path = std::env::temp_dir().join(format!("rumi_test_whitelist_{}.txt", std::process::id()));
+    std::fs::write(&path, "AAAACCCC\t1234\nAAAACCGG\t567\n").unwrap();
+    let header = get_header();
+    let record = |r: &[u8]| bam::record::Record::from_sam(&header, r).unwrap();
+    let records = vec![
+        record(b"read1	0	chr19	1000	60	8M	*	0	0	*	*	RX:Z:CAGTA	CR:Z:AAAACCCC"),
+        record(
+            b"read2	0	chr19	1000	60	8M	*	0	0	*	*	RX:Z:CAGTA	CR:Z:AAAACCCA	CY:Z:IIIIIII#	CB:Z:TTTT",
+        ),
+        record(b"read3	0	chr19	1000	60	8M	*	0	0	*	*	RX:Z:CAGTA	CR:Z:AAAACCCG"),
+        record(b"read4	0	chr19	1000	60	8M	*	0	0	*	*	RX:Z:CAGTA	CR:Z:TTTTTTTT"),
+        record(b"read5	0	chr19	1000	60	8M	*	0	0	*	*	RX:Z:CAGTA	CB:Z:AAAACCCC"),
+    ];
+    let config = Config::builder()
+        .per_cell(true)
+        .cell_whitelist(path.to_str().unwrap())
+        .group_only(true)
+        .build()
+        .unwrap();
+    let paired = Config::builder()
+        .per_cell(true)
+        .cell_whitelist(path.to_str().unwrap())
+        .is_paired(true)
+        .build()
+        .unwrap();
+    std::fs::remove_file(&path).unwrap();
+    let (grouped, _, _, stats) = group_reads(records, &config).unwrap();
+    assert_eq!(
+        (
+            stats.cell_corrected,
+            stats.cell_uncorrectable,
+            stats.missing_cell
+        ),
+        (1, 2, 1)
+    );
+    let cells: Vec<(&[u8], &[u8])> = grouped
+        .values()
+        .flat_map(|umis| umis.values())
+        .flat_map(|r| r.read.reads())
+        .map(|read| (read.qname(), read.aux(b"CB").unwrap().string()))
+        .collect();
+    assert_eq!(
+        cells,
+        vec![
+            (b"read1" as &[u8], b"AAAACCCC" as &[u8]),
+            (b"read2", b"AAAACCCC")
+        ]
+    );
+
+    // Mates get the corrected barcode of their read
+    let mut mates = MateBuffer::new(&paired, &header);
+    mates.push(
+        record(b"pair	99	chr19	1000	60	8M	=	1200	208	*	*	RX:Z:CAGTA	CR:Z:AAAACCCA	CB:Z:AAAACCCC"),
+        Output::Grouped,
+    );
+    mates.push(
+        record(b"pair	147	chr19	1200	60	8M	=	1000	-208	*	*	RX:Z:CAGTA	CR:Z:AAAACCCA"),
+        Output::Passthrough,
+    );
+    let ready = mates.ready();
+    assert_eq!(ready.len(), 2);
+    assert_eq!(ready[1].0.aux(b"CB").unwrap().string(), b"AAAACCCC");
+
+    assert!(Config::builder()
+        .cell_whitelist("whitelist.txt")
+        .build()
+        .is_err());
+}